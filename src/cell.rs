@@ -0,0 +1,98 @@
+// Named colors are the 16 colors set by SGR 30-37/40-47 and 90-97/100-107,
+// Indexed colors are set by 38;5/48;5
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Color {
+    #[default]
+    Default,
+    Named(u8),
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnderlineStyle {
+    #[default]
+    None,
+    Single,
+    Double,
+    Curly,
+    Dotted,
+    Dashed,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Attr {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub italic: bool,
+    // SGR 7, swap fg and bg
+    pub reverse: bool,
+    // SGR 8, only background is drawn, text is still copied
+    pub concealed: bool,
+    pub underline: UnderlineStyle,
+    // SGR 53
+    pub overline: bool,
+    // Default means same as fg
+    pub underline_color: Color,
+    // set by DECSCA, kept by selective erase
+    pub protected: bool,
+    // OSC 8 hyperlink, index into link table of console plus 1, 0 for none
+    pub link: u16,
+}
+
+impl Attr {
+    // SGR params of attributes, starting with reset
+    pub fn sgr_params(&self) -> String {
+        let mut params = vec![String::from("0")];
+        if self.bold {
+            params.push(String::from("1"));
+        }
+        if self.italic {
+            params.push(String::from("3"));
+        }
+        if self.reverse {
+            params.push(String::from("7"));
+        }
+        if self.concealed {
+            params.push(String::from("8"));
+        }
+        match self.underline {
+            UnderlineStyle::None => {}
+            UnderlineStyle::Single => params.push(String::from("4")),
+            UnderlineStyle::Double => params.push(String::from("4:2")),
+            UnderlineStyle::Curly => params.push(String::from("4:3")),
+            UnderlineStyle::Dotted => params.push(String::from("4:4")),
+            UnderlineStyle::Dashed => params.push(String::from("4:5")),
+        }
+        if self.overline {
+            params.push(String::from("53"));
+        }
+        for (color, base) in [(self.fg, 30), (self.bg, 40), (self.underline_color, 50)].iter() {
+            match *color {
+                Color::Default => {}
+                Color::Named(n @ 0..=7) if *base != 50 => {
+                    params.push((base + n as i32).to_string())
+                }
+                Color::Named(n) if *base != 50 => {
+                    params.push((base + 60 + n as i32 - 8).to_string())
+                }
+                Color::Named(n) | Color::Indexed(n) => params.push(format!("{}:5:{}", base + 8, n)),
+                Color::Rgb(r, g, b) => params.push(format!("{}:2::{}:{}:{}", base + 8, r, g, b)),
+            }
+        }
+        params.join(";")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cell {
+    pub ch: u8,
+    pub attr: Attr,
+}
+
+impl Cell {
+    pub fn new(ch: u8, attr: Attr) -> Cell {
+        Cell { ch, attr }
+    }
+}
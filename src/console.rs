@@ -1,240 +1,2826 @@
-use crate::screen_buffer::ScreenBuffer;
+use crate::cell::{Attr, Cell, Color, UnderlineStyle};
+use crate::parser::{Action, Parser};
+use crate::renderer::Renderer;
+use crate::screen_buffer::{PromptMark, ScreenBuffer};
+use crate::sixel;
+use crate::title::Title;
 use mray::algebra::Point2f;
 use mray::canvas::Canvas;
 use mray::graphic_object::{GraphicObject, Polygon2f};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CursorStyle {
+    Block,
+    Underline,
+    Bar,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BellMode {
+    // blend screen white for a moment
+    Flash,
+    // title is marked until window is focused
+    Mark,
+    // BEL to stderr of launching terminal
+    Audible,
+    Silent,
+}
+
+// bytes of Enter key, LNM always sends CR LF
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnterKey {
+    // line discipline translates it with ICRNL
+    Cr,
+    Lf,
+    CrLf,
+}
+
+// alpha blend color over canvas
+fn blend_rect(canvas: &mut Canvas, width: i32, pos: (i32, i32), size: (i32, i32), color: [f32; 4]) {
+    for y in pos.1..pos.1 + size.1 {
+        for x in pos.0..pos.0 + size.0 {
+            let index = ((x + y * width) * 3) as usize;
+            for (data, c) in canvas.data[index..index + 3].iter_mut().zip(color.iter()) {
+                *data = (*data as f32 * (1. - color[3]) + c * 255. * color[3]) as u8;
+            }
+        }
+    }
+}
+
+// pos is the left end of the baseline of underline
+fn draw_underline(
+    canvas: &mut Canvas,
+    width: i32,
+    pos: (i32, i32),
+    len: i32,
+    style: UnderlineStyle,
+    color: [f32; 4],
+) {
+    for x in pos.0..pos.0 + len {
+        let ys: &[i32] = match style {
+            UnderlineStyle::None => &[],
+            UnderlineStyle::Single => &[0],
+            UnderlineStyle::Double => &[-1, 1],
+            UnderlineStyle::Curly => match x % 4 {
+                0 => &[0],
+                1 => &[-1],
+                2 => &[0],
+                _ => &[1],
+            },
+            UnderlineStyle::Dotted if x % 4 < 2 => &[0],
+            UnderlineStyle::Dashed if x % 6 < 4 => &[0],
+            _ => &[],
+        };
+        for y in ys {
+            fill_rect(canvas, width, (x, pos.1 + y), (1, 1), color);
+        }
+    }
+}
+
 pub struct Console {
     size: (i32, i32),
+    // cell size, glyph size plus padding
     font_size: (i32, i32),
+    padding: (i32, i32),
     scaler: f32,
     pub canvas: Canvas,
-    csi_buf: Vec<u8>,
+    pub parser: Parser,
     screen: Vec<ScreenBuffer>,
     sid: usize,
+    palette: Vec<[f32; 4]>,
+    default_fg: [f32; 4],
+    default_bg: [f32; 4],
+    // bold text with named colors 0-7 uses bright colors 8-15
+    pub bold_is_bright: bool,
+    // colors are scaled by this when window is unfocused, 1 for no dimming
+    pub unfocused_brightness: f32,
+    // CSI 2 J pushes screen content to scrollback before clearing,
+    // otherwise only visible screen is cleared like xterm default
+    pub clear_to_scrollback: bool,
+    pub bell_mode: BellMode,
+    pub enter_key: EnterKey,
+    // end of visual bell
+    bell_flash: Option<std::time::Instant>,
+    // bell in unfocused window, for BellMode::Mark
+    bell_marked: bool,
+    // render sixel images, ignored otherwise
+    pub sixel: bool,
+    // alternate screen keeps its own scrollback, otherwise
+    // it has none and mouse wheel sends cursor keys
+    pub alt_screen_scrollback: bool,
+    // ?1049 saves and restores cursor, otherwise it is like ?47
+    pub alt_screen_save_cursor: bool,
+    // see set_bce
+    bce: bool,
+    // see set_tab_width
+    tab_width: i32,
+    // replaceable by embedders
+    pub glyph_fallback: GlyphFallback,
+    // glyph slant of SGR 3, upright text is not sheared
+    pub italic_shear: f32,
+    bracketed_paste: bool,
+    // multiline paste without bracketed paste must be pasted twice
+    pub paste_guard: bool,
+    pending_paste: Option<String>,
+    cursor_style: CursorStyle,
+    // restored by DECSCUSR 0 and resets
+    pub default_cursor_style: CursorStyle,
+    // DECCKM
+    app_cursor_keys: bool,
+    // DECKPAM/DECKPNM
+    app_keypad: bool,
+    // LNM, return sends CR LF
+    newline_mode: bool,
+    // DECTCEM
+    cursor_visible: bool,
+    // DECARM
+    auto_repeat: bool,
+    focus_reporting: bool,
+    focused: bool,
+    // steady cursor if false, restored by DECSCUSR 0 and resets
+    pub cursor_blink: bool,
+    // set by DECSCUSR, None for cursor_blink
+    cursor_blinking: Option<bool>,
+    // reset on activity, blink phase starts from it
+    blink_epoch: std::time::Instant,
+    // cursor stops blinking without activity, zero for never
+    pub blink_idle_timeout: std::time::Duration,
+    // XTSAVE
+    saved_modes: std::collections::HashMap<String, bool>,
+    title: Title,
+    // reported by OSC 7
+    cwd: Option<String>,
+    // OSC 8 URIs, cells refer to them by index plus 1
+    links: Vec<String>,
+    // synchronized output start time
+    sync_output: Option<std::time::Instant>,
+    // XTMODKEYS resources like modifyCursorKeys, indexed by Pp
+    modify_keys: [i32; 8],
+    // start and end cell of mouse selection
+    selection: Option<((i32, i32), (i32, i32))>,
+    // scrolled lines of screen when selection was last moved
+    selection_scrolled: u64,
+    // cell under mouse, None if outside window
+    pointer: Option<(i32, i32)>,
+    // blended over background of selected cells
+    pub selection_color: [f32; 4],
+    // debug overlay of frame time and throughput
+    pub show_overlay: bool,
+    overlay_text: String,
+    // bumped by palette and font size changes, older cached rows are redrawn
+    cache_generation: u64,
+    // render options of last frame, pub fields can change without a setter
+    cache_options: (u32, u32, bool, u32, bool, usize),
+    // indexed by screen row, see CachedRow
+    row_cache: Vec<Option<CachedRow>>,
+}
+
+// pixels of a rendered screen row, reused while cells and generation are unchanged
+struct CachedRow {
+    generation: u64,
+    cells: Vec<Cell>,
+    pixels: Vec<u8>,
+}
+
+// for logging
+fn esc_string(intermediate: &[u8], final_byte: u8) -> String {
+    format!(
+        "\x1b{}{}",
+        String::from_utf8_lossy(intermediate),
+        final_byte as char
+    )
+}
+
+fn csi_string(param: &[u8], intermediate: &[u8], final_byte: u8) -> String {
+    format!(
+        "\x1b[{}{}{}",
+        String::from_utf8_lossy(param),
+        String::from_utf8_lossy(intermediate),
+        final_byte as char
+    )
+}
+
+// path of file://host/path with percent-encoding decoded
+fn file_url_path(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("file://")?;
+    let path = &rest[rest.find('/')?..];
+    let mut bytes = Vec::new();
+    let mut iter = path.bytes();
+    while let Some(ch) = iter.next() {
+        if ch == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(ch);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// missing or 0 means default for counts and coordinates,
+// modes where 0 has its own meaning use unwrap_or(0) instead
+fn param_or(param: &str, default: i32) -> i32 {
+    match param.parse::<i32>() {
+        Ok(0) | Err(_) => default,
+        Ok(n) => n,
+    }
+}
+
+// xterm default palette
+fn default_palette() -> Vec<[f32; 4]> {
+    let named: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+    let level = |x: u8| if x == 0 { 0 } else { 55 + x * 40 };
+    let mut palette = Vec::new();
+    for index in 0..=255u8 {
+        let (r, g, b) = match index {
+            0..=15 => named[index as usize],
+            16..=231 => {
+                let i = index - 16;
+                (level(i / 36), level(i / 6 % 6), level(i % 6))
+            }
+            _ => {
+                let grey = 8 + 10 * (index - 232);
+                (grey, grey, grey)
+            }
+        };
+        palette.push(rgb_to_color(r, g, b));
+    }
+    palette
+}
+
+fn rgb_to_color(r: u8, g: u8, b: u8) -> [f32; 4] {
+    [r as f32 / 255., g as f32 / 255., b as f32 / 255., 1.]
+}
+
+// xterm color spec, rgb:r/g/b with 1 to 4 hex digits each, or #rrggbb
+pub fn parse_color_spec(spec: &str) -> Option<[f32; 4]> {
+    if let Some(hex) = spec.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        return Some(rgb_to_color(
+            (value >> 16) as u8,
+            (value >> 8) as u8,
+            value as u8,
+        ));
+    }
+    let mut iter = spec.strip_prefix("rgb:")?.split('/');
+    let mut color = [0., 0., 0., 1.];
+    for c in color[..3].iter_mut() {
+        let hex = iter.next()?;
+        if hex.is_empty() || hex.len() > 4 || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return None;
+        }
+        let max = (1u32 << (4 * hex.len())) - 1;
+        *c = u32::from_str_radix(hex, 16).ok()? as f32 / max as f32;
+    }
+    if iter.next().is_some() {
+        return None;
+    }
+    Some(color)
+}
+
+// reply of color queries
+fn color_spec(color: [f32; 4]) -> String {
+    let c = |x: f32| (x * 65535.).round() as u32;
+    format!(
+        "rgb:{:04x}/{:04x}/{:04x}",
+        c(color[0]),
+        c(color[1]),
+        c(color[2])
+    )
+}
+
+// draw chars without fsd glyph, same arguments as fill_rect plus char
+pub type GlyphFallback = fn(&mut Canvas, i32, (i32, i32), (i32, i32), u8, [f32; 4]);
+
+// hollow box inside cell
+fn tofu_box(
+    canvas: &mut Canvas,
+    width: i32,
+    pos: (i32, i32),
+    size: (i32, i32),
+    _: u8,
+    color: [f32; 4],
+) {
+    let (x, y) = (pos.0 + 2, pos.1 + 2);
+    let (w, h) = (size.0 - 4, size.1 - 4);
+    for (rect_pos, rect_size) in [
+        ((x, y), (w, 1)),
+        ((x, y + h - 1), (w, 1)),
+        ((x, y), (1, h)),
+        ((x + w - 1, y), (1, h)),
+    ]
+    .iter()
+    {
+        fill_rect(canvas, width, *rect_pos, *rect_size, color);
+    }
+}
+
+// canvas data is RGB24, width is in pixels
+fn fill_rect(canvas: &mut Canvas, width: i32, pos: (i32, i32), size: (i32, i32), color: [f32; 4]) {
+    for y in pos.1..pos.1 + size.1 {
+        for x in pos.0..pos.0 + size.0 {
+            let index = ((x + y * width) * 3) as usize;
+            for (data, c) in canvas.data[index..index + 3].iter_mut().zip(color.iter()) {
+                *data = (c * 255.) as u8;
+            }
+        }
+    }
+}
+
+const GLYPH_SIZE: (i32, i32) = (15, 20);
+
+// distinct OSC 8 URIs kept, later links are ignored
+const MAX_LINKS: usize = 4096;
+
+// xterm defaults, 1 is modifyCursorKeys, 2 modifyFunctionKeys, 4 modifyOtherKeys
+const DEFAULT_MODIFY_KEYS: [i32; 8] = [0, 2, 2, 0, 0, 0, 0, 0];
+
+// alternate screen has no scrollback
+fn new_screens(size: (i32, i32)) -> Vec<ScreenBuffer> {
+    let mut alt_screen = ScreenBuffer::new(size);
+    alt_screen.scrollback_limit = 0;
+    vec![ScreenBuffer::new(size), alt_screen]
 }
 
 impl Console {
     pub fn new(size: (i32, i32)) -> Console {
-        let font_size = (15, 20);
+        let font_size = GLYPH_SIZE;
         Console {
             size,
             font_size,
+            padding: (0, 0),
             scaler: 20.,
             canvas: Canvas::new((size.0 * font_size.0, size.1 * font_size.1)),
-            csi_buf: Vec::new(),
-            screen: vec![ScreenBuffer::new(size), ScreenBuffer::new(size)],
+            parser: Parser::new(),
+            screen: new_screens(size),
             sid: 0,
+            palette: default_palette(),
+            default_fg: [1., 0.6, 0., 1.],
+            default_bg: [0., 0., 0., 1.],
+            bold_is_bright: true,
+            unfocused_brightness: 1.,
+            clear_to_scrollback: false,
+            bell_mode: BellMode::Flash,
+            enter_key: EnterKey::Cr,
+            bell_flash: None,
+            bell_marked: false,
+            sixel: false,
+            alt_screen_scrollback: false,
+            alt_screen_save_cursor: true,
+            bce: true,
+            tab_width: 8,
+            glyph_fallback: tofu_box,
+            italic_shear: -0.2,
+            bracketed_paste: false,
+            paste_guard: true,
+            pending_paste: None,
+            cursor_style: CursorStyle::Bar,
+            default_cursor_style: CursorStyle::Bar,
+            app_cursor_keys: false,
+            app_keypad: false,
+            newline_mode: false,
+            cursor_visible: true,
+            auto_repeat: true,
+            focus_reporting: false,
+            focused: true,
+            cursor_blink: true,
+            cursor_blinking: None,
+            blink_epoch: std::time::Instant::now(),
+            blink_idle_timeout: std::time::Duration::from_secs(10),
+            saved_modes: std::collections::HashMap::new(),
+            title: Title::new("fsdterm"),
+            cwd: None,
+            links: Vec::new(),
+            sync_output: None,
+            modify_keys: DEFAULT_MODIFY_KEYS,
+            selection: None,
+            selection_scrolled: 0,
+            pointer: None,
+            selection_color: [0.3, 0.5, 1., 0.4],
+            show_overlay: false,
+            overlay_text: String::new(),
+            cache_generation: 0,
+            cache_options: (0, 0, false, 0, false, 0),
+            row_cache: Vec::new(),
         }
     }
 
+    pub fn get_cursor(&self) -> (i32, i32) {
+        self.screen[self.sid].get_render_data().1
+    }
+
     // for set env
     pub fn get_size(&self) -> (i32, i32) {
         self.size
     }
 
-    fn proc_csi(&mut self) -> Option<Vec<u8>> {
-        println!("{:?}", String::from_utf8(self.csi_buf.clone()).unwrap());
-        if self.csi_buf.is_empty() {
+    // current directory of shell, set by OSC 7
+    pub fn get_cwd(&self) -> Option<&str> {
+        self.cwd.as_deref()
+    }
+
+    // new title for window, if changed since last call
+    pub fn take_title(&mut self) -> Option<String> {
+        if !self.title.take_changed() {
             return None;
         }
-        if self.csi_buf[0] != 27 {
-            println!("csi_buf error");
-            return None;
+        let mut title = self.title.title().to_string();
+        if self.bell_marked {
+            title = format!("[bell] {}", title);
         }
-        let mut param = Vec::new();
-        let mut final_byte = None;
-        for ch in self.csi_buf[1..].iter() {
-            match ch {
-                0x30..=0x3F => {
-                    param.push(*ch);
+        if self.pending_paste.is_some() {
+            title = format!("[paste again to confirm] {}", title);
+        }
+        Some(title)
+    }
+
+    fn bell(&mut self) {
+        match self.bell_mode {
+            BellMode::Flash => {
+                self.bell_flash =
+                    Some(std::time::Instant::now() + std::time::Duration::from_millis(100));
+            }
+            BellMode::Mark => {
+                if !self.focused && !self.bell_marked {
+                    self.bell_marked = true;
+                    self.title.mark_changed();
                 }
-                0x40..=0x7F => {
-                    final_byte = Some(ch);
+            }
+            BellMode::Audible => eprint!("\x07"),
+            BellMode::Silent => {}
+        }
+    }
+
+    // XTWINOPS, return report
+    fn window_op(&mut self, params: &[i32]) -> Option<Vec<u8>> {
+        match params.first() {
+            // text area size in pixels
+            Some(14) => {
+                let canvas_size = self.get_canvas_size();
+                return Some(format!("\x1b[4;{};{}t", canvas_size.1, canvas_size.0).into_bytes());
+            }
+            // text area size in chars
+            Some(18) => {
+                return Some(format!("\x1b[8;{};{}t", self.size.1, self.size.0).into_bytes());
+            }
+            Some(20) => return Some(self.title.report_icon()),
+            Some(21) => return Some(self.title.report_title()),
+            // XTPUSHTITLE
+            Some(22) => self.title.push(params.get(1).copied().unwrap_or(0)),
+            // XTPOPTITLE
+            Some(23) => self.title.pop(params.get(1).copied().unwrap_or(0)),
+            _ => println!("Unimplemented window operation {:?}", params),
+        }
+        None
+    }
+
+    // OSC 4, index;spec pairs, spec ? is a query
+    fn set_palette(&mut self, text: &str) -> Option<Vec<u8>> {
+        let mut report = String::new();
+        let mut iter = text.split(';');
+        while let (Some(index), Some(spec)) = (iter.next(), iter.next()) {
+            let index = match index.parse::<usize>() {
+                Ok(index) if index < self.palette.len() => index,
+                _ => {
+                    println!("Invalid palette index {:?}", index);
+                    continue;
                 }
-                _ => {}
+            };
+            if spec == "?" {
+                let spec = color_spec(self.palette[index]);
+                report.push_str(&format!("\x1b]4;{};{}\x1b\\", index, spec));
+            } else if let Some(color) = parse_color_spec(spec) {
+                self.palette[index] = color;
+                self.mark_all_dirty();
+                self.invalidate_cache();
+            } else {
+                println!("Invalid color spec {:?}", spec);
             }
         }
-        let mut report = None;
-        match final_byte {
-            Some(b'D') => {
-                self.screen[self.sid].move_cursor(
-                    -String::from_utf8(param)
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap_or(1),
-                    0,
-                    false,
-                );
+        Some(report.into_bytes()).filter(|report| !report.is_empty())
+    }
+
+    // OSC 10 and 11, default fg and bg
+    fn set_default_color(&mut self, code: &str, spec: &str) -> Option<Vec<u8>> {
+        let color = if code == "10" {
+            &mut self.default_fg
+        } else {
+            &mut self.default_bg
+        };
+        if spec == "?" {
+            let reply = format!("\x1b]{};{}\x1b\\", code, color_spec(*color));
+            return Some(reply.into_bytes());
+        }
+        match parse_color_spec(spec) {
+            Some(new_color) => {
+                *color = new_color;
+                self.mark_all_dirty();
+                self.invalidate_cache();
             }
-            Some(b'C') => {
-                self.screen[self.sid].move_cursor(
-                    String::from_utf8(param)
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap_or(1),
-                    0,
-                    false,
-                );
+            None => println!("Invalid color spec {:?}", spec),
+        }
+        None
+    }
+
+    // return report
+    fn proc_osc(&mut self, osc: Vec<u8>) -> Option<Vec<u8>> {
+        let osc = String::from_utf8_lossy(&osc);
+        let mut iter = osc.splitn(2, ';');
+        let code = iter.next().unwrap();
+        let text = iter.next().unwrap_or("");
+        match code {
+            "0" => {
+                self.title.set_icon(text);
+                self.title.set_title(text);
             }
-            Some(b'A') => {
-                self.screen[self.sid].move_cursor(
-                    0,
-                    -String::from_utf8(param)
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap_or(1),
-                    false,
-                );
+            "1" => self.title.set_icon(text),
+            "2" => self.title.set_title(text),
+            "7" => match file_url_path(text) {
+                Some(path) => self.cwd = Some(path),
+                None => println!("Invalid cwd url {:?}", text),
+            },
+            "133" => {
+                let mark = match text.chars().next() {
+                    Some('A') => PromptMark::Prompt,
+                    Some('B') => PromptMark::Command,
+                    Some('C') => PromptMark::Output,
+                    Some('D') => PromptMark::End,
+                    _ => {
+                        println!("Unknown prompt mark {:?}", text);
+                        return None;
+                    }
+                };
+                self.screen[self.sid].mark(mark);
             }
-            Some(b'B') => {
-                self.screen[self.sid].move_cursor(
-                    0,
-                    String::from_utf8(param)
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap_or(1),
-                    false,
-                );
+            "4" => return self.set_palette(text),
+            "8" => self.set_link(text),
+            "10" | "11" => return self.set_default_color(code, text),
+            "1337" => println!("Ignored iTerm2 osc of {} bytes", text.len()),
+            _ => println!("Unimplemented osc sequence {:?}", osc),
+        }
+        None
+    }
+
+    pub fn set_pointer(&mut self, pos: Option<(i32, i32)>) {
+        self.pointer = pos;
+    }
+
+    // DECLRP reply of DECRQLP, no buttons pressed and page 1
+    fn report_locator(&self) -> Vec<u8> {
+        match self.pointer {
+            Some((x, y)) => format!("\x1b[1;0;{};{};1&w", y + 1, x + 1).into_bytes(),
+            // locator unavailable
+            None => b"\x1b[0&w".to_vec(),
+        }
+    }
+
+    // OSC 8, text is params;URI, empty URI ends link
+    fn set_link(&mut self, text: &str) {
+        let uri = text.split_once(';').map_or("", |x| x.1);
+        if uri.is_empty() {
+            self.screen[self.sid].set_link(0);
+            return;
+        }
+        let id = match self.links.iter().position(|link| link == uri) {
+            Some(id) => id,
+            None if self.links.len() < MAX_LINKS => {
+                self.links.push(uri.to_string());
+                self.links.len() - 1
             }
-            Some(b'H') => {
-                // ansi coodinate is 1..=n, not 0..n
-                let params = String::from_utf8(param)
-                    .unwrap()
-                    .split(';')
-                    .map(|x| x.parse::<i32>().unwrap_or(1) - 1)
-                    .collect::<Vec<i32>>();
-                if params.len() == 1 {
-                    self.screen[self.sid].move_cursor(1, 1, true);
-                } else {
-                    self.screen[self.sid].move_cursor(params[1], params[0], true);
-                }
+            None => {
+                println!("Too many links, ignored {:?}", uri);
+                return;
             }
-            Some(b'J') => {
-                self.screen[self.sid].erase_display(
-                    String::from_utf8(param)
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap_or(0),
-                );
+        };
+        self.screen[self.sid].set_link(id as u16 + 1);
+    }
+
+    // URI of OSC 8 link at screen position
+    pub fn link_at(&self, pos: (i32, i32)) -> Option<&str> {
+        let link = self.screen[self.sid].view_cell(pos.0, pos.1).attr.link;
+        self.links
+            .get((link as usize).checked_sub(1)?)
+            .map(|x| x.as_str())
+    }
+
+    pub fn start_selection(&mut self, pos: (i32, i32)) {
+        self.selection = Some((pos, pos));
+        self.selection_scrolled = self.screen[self.sid].scrolled();
+    }
+
+    // selection moves up with its text when screen scrolls,
+    // cleared when all of it is scrolled out
+    fn follow_scroll(&mut self) {
+        let (start, end) = match self.selection {
+            Some(selection) => selection,
+            None => return,
+        };
+        let scrolled = self.screen[self.sid].scrolled();
+        let n = scrolled
+            .saturating_sub(self.selection_scrolled)
+            .min(self.size.1 as u64) as i32;
+        if n == 0 {
+            return;
+        }
+        self.selection_scrolled = scrolled;
+        let (start, end) = ((start.0, start.1 - n), (end.0, end.1 - n));
+        if start.1 < 0 && end.1 < 0 {
+            self.selection = None;
+            return;
+        }
+        let clip = |pos: (i32, i32)| if pos.1 < 0 { (0, 0) } else { pos };
+        self.selection = Some((clip(start), clip(end)));
+    }
+
+    pub fn extend_selection(&mut self, pos: (i32, i32)) {
+        if let Some((start, _)) = self.selection {
+            self.selection = Some((start, pos));
+        }
+    }
+
+    // text to copy, a click without drag is not a selection
+    pub fn finish_selection(&mut self) -> Option<String> {
+        match self.selection {
+            Some((start, end)) if start != end => self.selected_text(false),
+            _ => {
+                self.selection = None;
+                None
             }
-            Some(b'K') => {
-                self.screen[self.sid].erase_line(
-                    String::from_utf8(param)
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap_or(0),
-                );
+        }
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection = None;
+    }
+
+    // linear cell index of selection, start <= end
+    fn selection_range(&self) -> Option<(i32, i32)> {
+        let (start, end) = self.selection?;
+        let start = start.0 + start.1 * self.size.0;
+        let end = end.0 + end.1 * self.size.0;
+        Some((start.min(end), start.max(end)))
+    }
+
+    // with SGR sequences of cell attributes if ansi,
+    // scrolled back lines are copied as shown
+    pub fn selected_text(&self, ansi: bool) -> Option<String> {
+        let (start, end) = self.selection_range()?;
+        let screen = &self.screen[self.sid];
+        let width = self.size.0;
+        Some(screen.text_range(
+            (start % width, screen.view_row(start / width)),
+            (end % width + 1, screen.view_row(end / width)),
+            ansi,
+        ))
+    }
+
+    // marked by OSC 133
+    pub fn last_output(&self) -> Option<String> {
+        self.screen[self.sid].last_output()
+    }
+
+    // both screens, colors are shared
+    pub fn mark_all_dirty(&mut self) {
+        for screen in self.screen.iter_mut() {
+            screen.mark_all_dirty();
+        }
+    }
+
+    // cached rows are redrawn on next render
+    fn invalidate_cache(&mut self) {
+        self.cache_generation += 1;
+    }
+
+    pub fn set_overlay_text(&mut self, text: String) {
+        self.overlay_text = text;
+    }
+
+    // for embedders that update changed cells only
+    pub fn take_damage(&mut self) -> Vec<((i32, i32), Cell)> {
+        self.screen[self.sid].take_damage()
+    }
+
+    // selection is in screen coordinates, cleared on scrolling
+    pub fn scroll_view(&mut self, n: i32) {
+        self.selection = None;
+        self.screen[self.sid].scroll_view(n);
+    }
+
+    // for lines wider than screen without autowrap
+    pub fn scroll_horizontal(&mut self, n: i32) -> bool {
+        self.selection = None;
+        self.screen[self.sid].scroll_horizontal(n)
+    }
+
+    // positive lines scroll back
+    pub fn wheel(&mut self, lines: i32) -> Option<Vec<u8>> {
+        if self.sid == 1 && !self.alt_screen_scrollback {
+            let key = self.cursor_key(if lines > 0 { b'A' } else { b'B' }, false, false);
+            return Some(key.repeat(lines.unsigned_abs() as usize));
+        }
+        self.scroll_view(lines);
+        None
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        let offset = self.screen[self.sid].view_offset();
+        self.scroll_view(-offset);
+    }
+
+    // OSC 133 prompt navigation
+    pub fn jump_prompt(&mut self, forward: bool) {
+        self.selection = None;
+        self.screen[self.sid].jump_prompt(forward);
+    }
+
+    // text of current screen
+    pub fn snapshot(&self) -> String {
+        self.screen[self.sid].text()
+    }
+
+    pub fn get_font_size(&self) -> (i32, i32) {
+        self.font_size
+    }
+
+    // extra space between cells, glyphs are not scaled
+    pub fn set_padding(&mut self, padding: (i32, i32)) {
+        self.padding = padding;
+        self.font_size = (GLYPH_SIZE.0 + padding.0, GLYPH_SIZE.1 + padding.1);
+        self.canvas = Canvas::new((
+            self.size.0 * self.font_size.0,
+            self.size.1 * self.font_size.1,
+        ));
+        self.invalidate_cache();
+    }
+
+    // canvas size in pixels
+    pub fn get_canvas_size(&self) -> (u32, u32) {
+        (
+            (self.size.0 * self.font_size.0) as u32,
+            (self.size.1 * self.font_size.1) as u32,
+        )
+    }
+
+    pub fn resize(&mut self, size: (i32, i32)) {
+        self.size = size;
+        self.selection = None;
+        self.canvas = Canvas::new((size.0 * self.font_size.0, size.1 * self.font_size.1));
+        for screen in self.screen.iter_mut() {
+            screen.resize(size);
+        }
+    }
+
+    // primary screen and its scrollback are kept while alternate screen is used,
+    // clear is for alternate screen on entering
+    fn switch_screen(&mut self, alt: bool, save_cursor: bool, clear: bool) {
+        if alt == (self.sid == 1) {
+            return;
+        }
+        self.selection = None;
+        if alt {
+            if save_cursor {
+                self.screen[0].save_cursor();
             }
-            Some(b'm') => {
-                // we do nothing here as we don't plan to support SGR
+            self.screen[1].scrollback_limit = if self.alt_screen_scrollback {
+                self.screen[0].scrollback_limit
+            } else {
+                0
+            };
+            // cursor position is shared like xterm
+            let cursor = self.get_cursor();
+            self.sid = 1;
+            if clear {
+                self.screen[1].erase_display(2, false);
             }
-            Some(b'n') => {
-                report = self.screen[self.sid].report_cursor(
-                    String::from_utf8(param)
-                        .unwrap()
-                        .parse::<i32>()
-                        .unwrap_or(0),
-                );
+            self.screen[1].move_cursor(cursor.0, cursor.1, true);
+        } else {
+            self.sid = 0;
+            if save_cursor {
+                self.screen[0].restore_cursor();
             }
-            Some(b'h') => {
-                if std::str::from_utf8(&self.csi_buf).unwrap() == "\x1b[?1049h" {
-                    self.sid = 1;
-                } else {
-                    println!(
-                        "Unimplemented csi sequence {:?}",
-                        String::from_utf8(self.csi_buf.clone()).unwrap()
-                    );
+        }
+        self.screen[self.sid].mark_all_dirty();
+    }
+
+    // csi h/l
+    fn set_mode(&mut self, mode: &str, value: bool) {
+        match mode {
+            "?1" => self.app_cursor_keys = value,
+            "?66" => self.app_keypad = value,
+            "20" => self.newline_mode = value,
+            "4" => {
+                for screen in self.screen.iter_mut() {
+                    screen.insert_mode = value;
                 }
             }
-            Some(b'l') => {
-                if std::str::from_utf8(&self.csi_buf).unwrap() == "\x1b[?1049l" {
-                    self.sid = 0;
-                } else {
-                    println!(
-                        "Unimplemented csi sequence {:?}",
-                        String::from_utf8(self.csi_buf.clone()).unwrap()
-                    );
+            "?7" => {
+                for screen in self.screen.iter_mut() {
+                    screen.autowrap = value;
                 }
             }
-            Some(_) => {
-                println!(
-                    "Unimplemented final byte {:?}",
-                    String::from_utf8(self.csi_buf.clone()).unwrap()
-                );
+            "?8" => self.auto_repeat = value,
+            "?69" => {
+                for screen in self.screen.iter_mut() {
+                    screen.set_lr_margin_mode(value);
+                }
+            }
+            "?25" => self.cursor_visible = value,
+            "?1004" => self.focus_reporting = value,
+            "?47" => self.switch_screen(value, false, false),
+            "?1049" => self.switch_screen(value, self.alt_screen_save_cursor, true),
+            "?2004" => self.bracketed_paste = value,
+            "?2026" => {
+                self.sync_output = if value {
+                    Some(std::time::Instant::now())
+                } else {
+                    None
+                };
             }
-            _ => {}
+            _ => println!("Unimplemented mode {:?}", mode),
         }
-        self.csi_buf.clear();
-        report
     }
 
-    pub fn put_char(&mut self, ch: u8) -> Option<Vec<u8>> {
-        if ch == 27 {
-            //self.proc_csi();
-            self.csi_buf = vec![27];
-            return None;
+    // None for unrecognized mode
+    fn get_mode(&self, mode: &str) -> Option<bool> {
+        match mode {
+            "?1" => Some(self.app_cursor_keys),
+            "?66" => Some(self.app_keypad),
+            "20" => Some(self.newline_mode),
+            "4" => Some(self.screen[self.sid].insert_mode),
+            "?7" => Some(self.screen[self.sid].autowrap),
+            "?8" => Some(self.auto_repeat),
+            "?69" => Some(self.screen[self.sid].lr_margin_mode()),
+            "?25" => Some(self.cursor_visible),
+            "?1004" => Some(self.focus_reporting),
+            "?47" | "?1049" => Some(self.sid == 1),
+            "?2004" => Some(self.bracketed_paste),
+            "?2026" => Some(self.sync_output.is_some()),
+            _ => None,
         }
+    }
 
-        if !self.csi_buf.is_empty() {
-            if self.csi_buf.len() == 1 && ch == b'[' {
-                self.csi_buf.push(ch);
-                return None;
-            }
-            if ch >= 0x40 && ch < 0x80 {
-                self.csi_buf.push(ch);
-                return self.proc_csi();
+    // known modes like ?1=0 ?7=1
+    pub fn modes(&self) -> String {
+        [
+            "4", "20", "?1", "?7", "?8", "?25", "?66", "?69", "?1004", "?1049", "?2004", "?2026",
+        ]
+        .iter()
+        .map(|mode| format!("{}={}", mode, self.get_mode(mode).unwrap() as i32))
+        .collect::<Vec<String>>()
+        .join(" ")
+    }
+
+    // XTSAVE and XTRESTORE, param is like ?1000;1049
+    fn save_modes(&mut self, param: &str, restore: bool) {
+        for mode in param.trim_start_matches('?').split(';') {
+            let mode = format!("?{}", mode);
+            if restore {
+                if let Some(value) = self.saved_modes.get(&mode).copied() {
+                    self.set_mode(&mode, value);
+                }
+            } else if let Some(value) = self.get_mode(&mode) {
+                self.saved_modes.insert(mode, value);
             }
-            self.csi_buf.push(ch);
+        }
+    }
+
+    // DECRQM, reply CSI Ps ; value $ y
+    fn report_mode(&self, mode: &str) -> Vec<u8> {
+        let value = match self.get_mode(mode) {
+            Some(true) => 1,
+            Some(false) => 2,
+            None => 0,
+        };
+        format!("\x1b[{};{}$y", mode, value).into_bytes()
+    }
+
+    // return bytes to be written to pty
+    pub fn focus(&mut self, focused: bool) -> Option<Vec<u8>> {
+        self.focused = focused;
+        if focused && self.bell_marked {
+            self.bell_marked = false;
+            self.title.mark_changed();
+        }
+        if !self.focus_reporting {
             return None;
         }
+        if focused {
+            Some(b"\x1b[I".to_vec())
+        } else {
+            Some(b"\x1b[O".to_vec())
+        }
+    }
 
-        if ch == 13 {
-            self.screen[self.sid].set_char(ch, false);
+    // CSI u encoding of key ch (unshifted), None if traditional encoding is used
+    pub fn modify_other_key(&self, ch: u8, shift: bool, ctrl: bool) -> Option<Vec<u8>> {
+        // level 1 only encodes combos without traditional bytes
+        let ambiguous = ctrl && (shift || !ch.is_ascii_lowercase());
+        let level = self.modify_keys[4];
+        if !ctrl || level <= 0 || (level == 1 && !ambiguous) {
             return None;
         }
-        self.screen[self.sid].set_char(ch, true);
-        None
+        let modifier = 1 + shift as i32 + 4 * ctrl as i32;
+        Some(format!("\x1b[{};{}u", ch, modifier).into_bytes())
     }
 
-    pub fn render(&mut self) {
-        let (buffer, cursor) = self.screen[self.sid].get_render_data();
-        self.canvas.flush();
-        for x in 0..self.size.0 {
-            for y in 0..self.size.1 {
-                let ch = buffer[(x + y * self.size.0) as usize];
-                for graphic_object in mray::fsd::fsd(char::from(ch))
-                    .shift(Point2f::from_floats(-0.5, -0.5))
-                    .shear(-0.2)
-                    .shift(Point2f::from_floats(0.5, 0.5))
-                    .zoom(self.scaler as f32)
-                    .shift(Point2f::from_floats(
-                        (self.font_size.0 * x) as f32,
-                        (self.font_size.1 * y) as f32,
-                    ))
-                    .into_iter()
-                {
-                    let mut polygon = graphic_object
-                        .as_any()
-                        .downcast_ref::<Polygon2f>()
-                        .unwrap()
-                        .clone();
-                    polygon.border_color = [1., 0.6, 0., 1.];
-                    polygon.color = [1., 0.6, 0., 0.5];
-                    polygon.render(&mut self.canvas);
+    pub fn auto_repeat(&self) -> bool {
+        self.auto_repeat
+    }
+
+    // arrow keys, ch is one of ABCD, modifiers are encoded per modifyCursorKeys
+    pub fn cursor_key(&self, ch: u8, shift: bool, ctrl: bool) -> Vec<u8> {
+        let modifier = 1 + shift as i32 + 4 * ctrl as i32;
+        let ch = char::from(ch);
+        match self.modify_keys[1] {
+            level if modifier == 1 || level < 0 => {
+                if self.app_cursor_keys {
+                    format!("\x1bO{}", ch).into_bytes()
+                } else {
+                    format!("\x1b[{}", ch).into_bytes()
                 }
             }
+            0 => format!("\x1bO{}{}", modifier, ch).into_bytes(),
+            3 => format!("\x1b[>1;{}{}", modifier, ch).into_bytes(),
+            _ => format!("\x1b[1;{}{}", modifier, ch).into_bytes(),
         }
-        // cursor render
-        let ch = b'|';
-        for graphic_object in mray::fsd::fsd(char::from(ch))
-            .zoom(self.scaler as f32)
-            .shift(Point2f::from_floats(
-                (self.font_size.0 * cursor.0) as f32,
-                (self.font_size.1 * cursor.1) as f32,
-            ))
-            .into_iter()
-        {
-            graphic_object.render(&mut self.canvas);
+    }
+
+    // XTMODKEYS, CSI > Pp ; Pv m, missing value resets resource
+    fn set_modify_keys(&mut self, params: &[i32]) {
+        match *params {
+            [] => self.modify_keys = DEFAULT_MODIFY_KEYS,
+            [resource, ..] if !(0..8).contains(&resource) => {
+                println!("Unknown key modifier resource {}", resource);
+            }
+            [resource] => {
+                self.modify_keys[resource as usize] = DEFAULT_MODIFY_KEYS[resource as usize]
+            }
+            [resource, value, ..] => self.modify_keys[resource as usize] = value,
+        }
+    }
+
+    // XTQMODKEYS, CSI ? Pp m
+    fn report_modify_keys(&self, resource: i32) -> Option<Vec<u8>> {
+        let value = self.modify_keys.get(resource as usize)?;
+        Some(format!("\x1b[>{};{}m", resource, value).into_bytes())
+    }
+
+    // keypad enter sends ESC O M in application keypad mode
+    pub fn enter_key(&self, keypad: bool) -> Vec<u8> {
+        if keypad && self.app_keypad {
+            return vec![27, b'O', b'M'];
+        }
+        match self.enter_key {
+            _ if self.newline_mode => vec![b'\r', b'\n'],
+            EnterKey::Cr => vec![b'\r'],
+            EnterKey::Lf => vec![b'\n'],
+            EnterKey::CrLf => vec![b'\r', b'\n'],
+        }
+    }
+
+    // text as if typed, newlines are sent like Enter key
+    pub fn typed_bytes(&self, text: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for &ch in text {
+            if ch == b'\n' {
+                result.extend(self.enter_key(false));
+            } else {
+                result.push(ch);
+            }
         }
+        result
+    }
+
+    // return bytes to be written to pty
+    pub fn paste(&mut self, text: &str) -> Option<Vec<u8>> {
+        if self.bracketed_paste {
+            let mut result = b"\x1b[200~".to_vec();
+            result.extend(text.bytes());
+            result.extend(b"\x1b[201~");
+            return Some(result);
+        }
+        if self.paste_guard && text.contains('\n') {
+            // held paste is shown in title
+            self.title.mark_changed();
+            if self.pending_paste.as_deref() != Some(text) {
+                self.pending_paste = Some(text.to_string());
+                return None;
+            }
+            self.pending_paste = None;
+        }
+        Some(text.bytes().collect())
+    }
+
+    pub fn cancel_paste(&mut self) {
+        if self.pending_paste.take().is_some() {
+            self.title.mark_changed();
+        }
+    }
+
+    // background color erase like xterm, off is like linux console
+    pub fn set_bce(&mut self, bce: bool) {
+        self.bce = bce;
+        for screen in self.screen.iter_mut() {
+            screen.bce = bce;
+        }
+    }
+
+    // default tab stops, also used after reset
+    pub fn set_tab_width(&mut self, width: i32) {
+        self.tab_width = width;
+        for screen in self.screen.iter_mut() {
+            screen.set_tab_width(width);
+        }
+    }
+
+    // RIS
+    pub fn reset(&mut self) {
+        self.screen = new_screens(self.size);
+        self.set_bce(self.bce);
+        self.set_tab_width(self.tab_width);
+        self.sid = 0;
+        self.bracketed_paste = false;
+        self.pending_paste = None;
+        self.cursor_style = self.default_cursor_style;
+        self.cursor_blinking = None;
+        self.app_cursor_keys = false;
+        self.app_keypad = false;
+        self.newline_mode = false;
+        self.cursor_visible = true;
+        self.auto_repeat = true;
+        self.focus_reporting = false;
+        self.saved_modes.clear();
+        self.title.clear_stack();
+        self.cwd = None;
+        self.links.clear();
+        self.sync_output = None;
+        self.modify_keys = DEFAULT_MODIFY_KEYS;
+        self.selection = None;
+    }
+
+    // DECSTR
+    fn soft_reset(&mut self) {
+        self.screen[self.sid].soft_reset();
+        self.cursor_style = self.default_cursor_style;
+        self.cursor_blinking = None;
+    }
+
+    // DECSCUSR, odd params blink and even params are steady
+    fn set_cursor_style(&mut self, param: i32) {
+        self.cursor_style = match param {
+            0 => self.default_cursor_style,
+            1 | 2 => CursorStyle::Block,
+            3 | 4 => CursorStyle::Underline,
+            5 | 6 => CursorStyle::Bar,
+            _ => {
+                println!("Unsupported cursor style {}", param);
+                return;
+            }
+        };
+        self.cursor_blinking = if param == 0 {
+            None
+        } else {
+            Some(param % 2 == 1)
+        };
+    }
+
+    fn cursor_blinking(&self) -> bool {
+        self.cursor_blinking.unwrap_or(self.cursor_blink)
+    }
+
+    // DECSCUSR param of current style
+    fn cursor_style_param(&self) -> i32 {
+        let param = match self.cursor_style {
+            CursorStyle::Block => 1,
+            CursorStyle::Underline => 3,
+            CursorStyle::Bar => 5,
+        };
+        if self.cursor_blinking() {
+            param
+        } else {
+            param + 1
+        }
+    }
+
+    fn proc_dcs(&mut self, dcs: &[u8]) -> Option<Vec<u8>> {
+        // DECRQSS
+        if let Some(setting) = dcs.strip_prefix(b"$q") {
+            let reply = match setting {
+                b"m" => Some(format!("{}m", self.screen[self.sid].sgr_string())),
+                b"r" => {
+                    let (top, bottom) = self.screen[self.sid].get_margin();
+                    Some(format!("{};{}r", top + 1, bottom + 1))
+                }
+                b" q" => Some(format!("{} q", self.cursor_style_param())),
+                _ => None,
+            };
+            return Some(match reply {
+                Some(reply) => format!("\x1bP1$r{}\x1b\\", reply).into_bytes(),
+                None => b"\x1bP0$r\x1b\\".to_vec(),
+            });
+        }
+        // tmux passthrough, DCS tmux; ESC ESC ... ST, wrapped sequence is ignored
+        if dcs.starts_with(b"tmux;") {
+            return None;
+        }
+        // params followed by q
+        let sixel = dcs
+            .iter()
+            .position(|ch| !ch.is_ascii_digit() && *ch != b';')
+            .filter(|&end| dcs[end] == b'q');
+        if let Some(end) = sixel {
+            if self.sixel {
+                let image = sixel::parse(&dcs[end + 1..], &self.palette[..16]);
+                let rows = (image.height + self.font_size.1 - 1) / self.font_size.1;
+                self.screen[self.sid].add_image(image, rows);
+            } else {
+                println!("Ignored sixel image of {} bytes", dcs.len());
+            }
+        } else {
+            println!("Unimplemented dcs {:?}", String::from_utf8_lossy(dcs));
+        }
+        None
+    }
+
+    fn proc_esc(&mut self, intermediate: &[u8], final_byte: u8) {
+        // S7C1T and S8C1T, replies are always 7-bit
+        if intermediate == b" " && (final_byte == b'F' || final_byte == b'G') {
+            return;
+        }
+        if !intermediate.is_empty() {
+            println!(
+                "Unimplemented escape sequence {:?}",
+                esc_string(intermediate, final_byte)
+            );
+            return;
+        }
+        match final_byte {
+            b'M' => self.screen[self.sid].reverse_index(),
+            b'c' => self.reset(),
+            b'H' => self.screen[self.sid].set_tab_stop(),
+            b'7' => self.screen[self.sid].save_cursor(),
+            b'8' => self.screen[self.sid].restore_cursor(),
+            b'=' => self.app_keypad = true,
+            b'>' => self.app_keypad = false,
+            // ST, string is already dispatched by parser
+            b'\\' => {}
+            _ => {
+                println!(
+                    "Unimplemented escape sequence {:?}",
+                    esc_string(intermediate, final_byte)
+                );
+            }
+        }
+    }
+
+    fn proc_csi(&mut self, param: Vec<u8>, intermediate: &[u8], final_byte: u8) -> Option<Vec<u8>> {
+        println!("{:?}", csi_string(&param, intermediate, final_byte));
+        let mut report = None;
+        match final_byte {
+            b'D' | b'C' | b'A' | b'B' => {
+                let n = param_or(&String::from_utf8(param).unwrap(), 1);
+                let (x, y) = match final_byte {
+                    b'D' => (-n, 0),
+                    b'C' => (n, 0),
+                    b'A' => (0, -n),
+                    _ => (0, n),
+                };
+                self.screen[self.sid].move_cursor(x, y, false);
+            }
+            b'H' => {
+                // ansi coodinate is 1..=n, not 0..n
+                let params = String::from_utf8(param)
+                    .unwrap()
+                    .split(';')
+                    .map(|x| param_or(x, 1) - 1)
+                    .collect::<Vec<i32>>();
+                let x = params.get(1).copied().unwrap_or(0);
+                self.screen[self.sid].move_cursor(x, params[0], true);
+            }
+            b'I' => {
+                self.screen[self.sid].forward_tab(param_or(&String::from_utf8(param).unwrap(), 1));
+            }
+            // CHA and HPA
+            b'G' | b'`' => {
+                let x = param_or(&String::from_utf8(param).unwrap(), 1) - 1;
+                let y = self.get_cursor().1;
+                self.screen[self.sid].move_cursor(x, y, true);
+            }
+            // VPA
+            b'd' => {
+                let x = self.get_cursor().0;
+                let y = param_or(&String::from_utf8(param).unwrap(), 1) - 1;
+                self.screen[self.sid].move_cursor(x, y, true);
+            }
+            b'Z' => {
+                self.screen[self.sid].back_tab(param_or(&String::from_utf8(param).unwrap(), 1));
+            }
+            b'S' => {
+                self.screen[self.sid].scroll_up(param_or(&String::from_utf8(param).unwrap(), 1));
+            }
+            b'T' => {
+                let param = String::from_utf8(param).unwrap();
+                // 5 params form is mouse highlight tracking
+                if param.split(';').count() == 1 {
+                    self.screen[self.sid].scroll_down(param_or(&param, 1));
+                } else {
+                    println!("Unimplemented mouse highlight tracking {:?}", param);
+                }
+            }
+            b's' if param.starts_with(b"?") => {
+                self.save_modes(&String::from_utf8(param).unwrap(), false);
+            }
+            b'r' if param.starts_with(b"?") => {
+                self.save_modes(&String::from_utf8(param).unwrap(), true);
+            }
+            // DECSLRM, conflicts with SCOSC without DECLRMM
+            b's' if self.screen[self.sid].lr_margin_mode() => {
+                let params = String::from_utf8(param)
+                    .unwrap()
+                    .split(';')
+                    .map(|x| x.parse::<i32>().unwrap_or(0) - 1)
+                    .collect::<Vec<i32>>();
+                let left = params[0].max(0);
+                let right = match params.get(1) {
+                    Some(&right) if right >= 0 => right,
+                    _ => self.size.0 - 1,
+                };
+                self.screen[self.sid].set_lr_margin(left, right);
+            }
+            b'@' => {
+                self.screen[self.sid].insert_chars(param_or(&String::from_utf8(param).unwrap(), 1));
+            }
+            b'P' => {
+                self.screen[self.sid].delete_chars(param_or(&String::from_utf8(param).unwrap(), 1));
+            }
+            b'X' => {
+                self.screen[self.sid].erase_chars(param_or(&String::from_utf8(param).unwrap(), 1));
+            }
+            b'r' => {
+                let params = String::from_utf8(param)
+                    .unwrap()
+                    .split(';')
+                    .map(|x| x.parse::<i32>().unwrap_or(0) - 1)
+                    .collect::<Vec<i32>>();
+                let top = params[0].max(0);
+                let bottom = match params.get(1) {
+                    Some(&bottom) if bottom >= 0 => bottom,
+                    _ => self.size.1 - 1,
+                };
+                self.screen[self.sid].set_margin(top, bottom);
+            }
+            b't' => {
+                let params = String::from_utf8(param)
+                    .unwrap()
+                    .split(';')
+                    .map(|x| x.parse::<i32>().unwrap_or(0))
+                    .collect::<Vec<i32>>();
+                report = self.window_op(&params);
+            }
+            b'g' => {
+                self.screen[self.sid].clear_tab_stop(
+                    String::from_utf8(param)
+                        .unwrap()
+                        .parse::<i32>()
+                        .unwrap_or(0),
+                );
+            }
+            b'J' | b'K' => {
+                // DECSED and DECSEL with ? marker
+                let selective = param.starts_with(b"?");
+                let param = String::from_utf8(param)
+                    .unwrap()
+                    .trim_start_matches('?')
+                    .parse::<i32>()
+                    .unwrap_or(0);
+                if final_byte == b'K' {
+                    self.screen[self.sid].erase_line(param, selective);
+                } else {
+                    if param == 2 && self.clear_to_scrollback {
+                        self.screen[self.sid].save_screen();
+                    }
+                    self.screen[self.sid].erase_display(param, selective);
+                }
+            }
+            // DECSCA, 1 protects and 0 or 2 does not
+            b'q' if intermediate == b"\"" => {
+                let param = String::from_utf8(param)
+                    .unwrap()
+                    .parse::<i32>()
+                    .unwrap_or(0);
+                self.screen[self.sid].set_protected(param == 1);
+            }
+            // XTMODKEYS
+            b'm' if param.starts_with(b">") => {
+                let params = String::from_utf8(param[1..].to_vec())
+                    .unwrap()
+                    .split(';')
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.parse::<i32>().unwrap_or(0))
+                    .collect::<Vec<i32>>();
+                self.set_modify_keys(&params);
+            }
+            b'm' if param.starts_with(b"?") => {
+                let resource = String::from_utf8(param[1..].to_vec())
+                    .unwrap()
+                    .parse::<i32>()
+                    .unwrap_or(0);
+                report = self.report_modify_keys(resource);
+            }
+            b'm' => {
+                let params = String::from_utf8(param)
+                    .unwrap()
+                    .split(';')
+                    .map(|x| {
+                        x.split(':')
+                            .map(|y| y.parse::<i32>().unwrap_or(0))
+                            .collect::<Vec<i32>>()
+                    })
+                    .collect::<Vec<Vec<i32>>>();
+                self.screen[self.sid].sgr(&params);
+            }
+            b'n' => {
+                report = self.screen[self.sid].report_cursor(
+                    String::from_utf8(param)
+                        .unwrap()
+                        .parse::<i32>()
+                        .unwrap_or(0),
+                );
+            }
+            b'q' if intermediate == b" " => {
+                self.set_cursor_style(
+                    String::from_utf8(param)
+                        .unwrap()
+                        .parse::<i32>()
+                        .unwrap_or(0),
+                );
+            }
+            b'p' if intermediate == b"!" => self.soft_reset(),
+            b'|' if intermediate == b"'" => report = Some(self.report_locator()),
+            b'p' if intermediate == b"$" => {
+                report = Some(self.report_mode(&String::from_utf8(param).unwrap()));
+            }
+            b'h' => self.set_mode(&String::from_utf8(param).unwrap(), true),
+            b'l' => self.set_mode(&String::from_utf8(param).unwrap(), false),
+            _ => {
+                println!(
+                    "Unimplemented csi sequence {:?}",
+                    csi_string(&param, intermediate, final_byte)
+                );
+            }
+        }
+        report
+    }
+
+    pub fn put_char(&mut self, ch: u8) -> Option<Vec<u8>> {
+        let mut report = None;
+        match self.parser.advance(ch)? {
+            // DEL is a filler, ignored on output
+            Action::Print(0x7f) => {}
+            Action::Print(ch) => self.screen[self.sid].set_char(ch, true),
+            // CAN and SUB only abort sequences
+            Action::Execute(0x18) | Action::Execute(0x1a) => {}
+            Action::Execute(7) => self.bell(),
+            // VT and FF are LF, LNM adds CR
+            Action::Execute(10) | Action::Execute(11) | Action::Execute(12) => {
+                self.screen[self.sid].set_char(b'\n', true);
+                if self.newline_mode {
+                    self.screen[self.sid].set_char(b'\r', true);
+                }
+            }
+            Action::Execute(ch) => self.screen[self.sid].set_char(ch, true),
+            Action::EscDispatch {
+                intermediate,
+                final_byte,
+            } => self.proc_esc(&intermediate, final_byte),
+            Action::CsiDispatch {
+                param,
+                intermediate,
+                final_byte,
+            } => report = self.proc_csi(param, &intermediate, final_byte),
+            Action::OscDispatch(osc) => report = self.proc_osc(osc),
+            Action::DcsDispatch(dcs) => report = self.proc_dcs(&dcs),
+        }
+        self.follow_scroll();
+        report
+    }
+
+    // false during synchronized output, unless it takes too long at now
+    pub fn should_render(&self, now: std::time::Instant) -> bool {
+        match self.sync_output {
+            Some(start) => {
+                now.saturating_duration_since(start) > std::time::Duration::from_millis(200)
+            }
+            None => true,
+        }
+    }
+
+    // pty output or key press
+    pub fn activity(&mut self) {
+        self.blink_epoch = std::time::Instant::now();
+    }
+
+    fn cursor_idle(&self, now: std::time::Instant) -> bool {
+        self.blink_idle_timeout != std::time::Duration::from_secs(0)
+            && now.saturating_duration_since(self.blink_epoch) > self.blink_idle_timeout
+    }
+
+    // blink phase at now, always on for steady or idle cursor
+    fn cursor_on(&self, now: std::time::Instant) -> bool {
+        !self.cursor_blinking()
+            || self.cursor_idle(now)
+            || now.saturating_duration_since(self.blink_epoch).as_millis() % 1000 < 500
+    }
+
+    fn resolve_color(&self, color: Color, default: [f32; 4]) -> [f32; 4] {
+        match color {
+            Color::Default => default,
+            Color::Named(index) | Color::Indexed(index) => self.palette[index as usize],
+            Color::Rgb(r, g, b) => rgb_to_color(r, g, b),
+        }
+    }
+
+    // bold brightens named fg first, then reverse swaps fg and bg,
+    // including default colors, dimmed if unfocused
+    fn resolve_colors(&self, attr: &Attr) -> ([f32; 4], [f32; 4]) {
+        let mut fg = attr.fg;
+        if self.bold_is_bright && attr.bold {
+            if let Color::Named(index @ 0..=7) = fg {
+                fg = Color::Named(index + 8);
+            }
+        }
+        let mut fg = self.resolve_color(fg, self.default_fg);
+        let mut bg = self.resolve_color(attr.bg, self.default_bg);
+        if !self.focused {
+            for c in fg[..3].iter_mut().chain(bg[..3].iter_mut()) {
+                *c *= self.unfocused_brightness;
+            }
+        }
+        if attr.reverse {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+
+    // render and hand canvas to renderer, presenting is up to caller
+    pub fn draw(&mut self, renderer: &mut dyn Renderer) {
+        self.render();
+        renderer.update_frame(&self.canvas.data, self.get_canvas_size());
+    }
+
+    pub fn render(&mut self) {
+        self.render_at(std::time::Instant::now());
+    }
+
+    // now decides cursor blink phase and bell flash
+    fn render_at(&mut self, now: std::time::Instant) {
+        let (_, cursor) = self.screen[self.sid].get_render_data();
+        let view_offset = self.screen[self.sid].view_offset();
+        let h_offset = self.screen[self.sid].h_offset();
+        let width = self.size.0 * self.font_size.0;
+        let selection = self.selection_range();
+        let options = (
+            self.scaler.to_bits(),
+            self.italic_shear.to_bits(),
+            self.bold_is_bright,
+            self.unfocused_brightness.to_bits(),
+            self.focused,
+            self.glyph_fallback as usize,
+        );
+        if options != self.cache_options {
+            self.cache_options = options;
+            self.invalidate_cache();
+        }
+        let row_len = (width * self.font_size.1 * 3) as usize;
+        self.row_cache.resize_with(self.size.1 as usize, || None);
+        for y in 0..self.size.1 {
+            let cells: Vec<Cell> = (0..self.size.0)
+                .map(|x| self.screen[self.sid].view_cell(x, y))
+                .collect();
+            // selection is blended per cell, selected rows are not cached
+            let row = (y * self.size.0, (y + 1) * self.size.0);
+            let selected = matches!(selection, Some((start, end)) if start < row.1 && row.0 <= end);
+            let pixels = y as usize * row_len..(y as usize + 1) * row_len;
+            if let Some(cached) = &self.row_cache[y as usize] {
+                if !selected && cached.generation == self.cache_generation && cached.cells == cells
+                {
+                    self.canvas.data[pixels].copy_from_slice(&cached.pixels);
+                    continue;
+                }
+            }
+            self.render_row(y, &cells, selection, width);
+            self.row_cache[y as usize] = if selected {
+                None
+            } else {
+                Some(CachedRow {
+                    generation: self.cache_generation,
+                    cells,
+                    pixels: self.canvas.data[pixels].to_vec(),
+                })
+            };
+        }
+        self.render_images(width, view_offset);
+        let cursor = (cursor.0 - h_offset, cursor.1 + view_offset);
+        if cursor.0 >= 0 && cursor.1 < self.size.1 {
+            self.render_cursor(cursor, width, now);
+        }
+        if self.show_overlay {
+            self.render_overlay(width);
+        }
+        if let Some(end) = self.bell_flash {
+            if now < end {
+                let canvas_size = (width, self.size.1 * self.font_size.1);
+                blend_rect(
+                    &mut self.canvas,
+                    width,
+                    (0, 0),
+                    canvas_size,
+                    [1., 1., 1., 0.3],
+                );
+            } else {
+                self.bell_flash = None;
+            }
+        }
+    }
+
+    // row is filled with default bg first, dimmed like cells
+    fn render_row(&mut self, y: i32, cells: &[Cell], selection: Option<(i32, i32)>, width: i32) {
+        let (_, default_bg) = self.resolve_colors(&Attr::default());
+        fill_rect(
+            &mut self.canvas,
+            width,
+            (0, self.font_size.1 * y),
+            (width, self.font_size.1),
+            default_bg,
+        );
+        for (x, cell) in (0..).zip(cells.iter()) {
+            let (fg, bg) = self.resolve_colors(&cell.attr);
+            // row is filled with default bg
+            if bg != default_bg {
+                fill_rect(
+                    &mut self.canvas,
+                    width,
+                    (self.font_size.0 * x, self.font_size.1 * y),
+                    self.font_size,
+                    bg,
+                );
+            }
+            if let Some((start, end)) = selection {
+                let index = x + y * self.size.0;
+                if start <= index && index <= end {
+                    blend_rect(
+                        &mut self.canvas,
+                        width,
+                        (self.font_size.0 * x, self.font_size.1 * y),
+                        self.font_size,
+                        self.selection_color,
+                    );
+                }
+            }
+            if cell.attr.concealed {
+                continue;
+            }
+            if cell.attr.underline != UnderlineStyle::None {
+                let color = self.resolve_color(cell.attr.underline_color, fg);
+                draw_underline(
+                    &mut self.canvas,
+                    width,
+                    (self.font_size.0 * x, self.font_size.1 * (y + 1) - 2),
+                    self.font_size.0,
+                    cell.attr.underline,
+                    color,
+                );
+            }
+            if cell.attr.overline {
+                fill_rect(
+                    &mut self.canvas,
+                    width,
+                    (self.font_size.0 * x, self.font_size.1 * y),
+                    (self.font_size.0, 1),
+                    fg,
+                );
+            }
+            self.draw_glyph(
+                cell.ch,
+                (self.font_size.0 * x, self.font_size.1 * y),
+                if cell.attr.italic {
+                    self.italic_shear
+                } else {
+                    0.
+                },
+                fg,
+            );
+        }
+    }
+
+    // pos is top left of cell, glyph is centered in padding
+    fn draw_glyph(&mut self, ch: u8, pos: (i32, i32), shear: f32, color: [f32; 4]) {
+        let graphic_objects: Vec<_> = mray::fsd::fsd(char::from(ch))
+            .shift(Point2f::from_floats(-0.5, -0.5))
+            .shear(shear)
+            .shift(Point2f::from_floats(0.5, 0.5))
+            .zoom(self.scaler as f32)
+            .shift(Point2f::from_floats(
+                (pos.0 + self.padding.0 / 2) as f32,
+                (pos.1 + self.padding.1 / 2) as f32,
+            ))
+            .into_iter()
+            .collect();
+        // blank cells have no glyph
+        if graphic_objects.is_empty() && ch != 0 && ch != b' ' {
+            let width = self.size.0 * self.font_size.0;
+            (self.glyph_fallback)(&mut self.canvas, width, pos, self.font_size, ch, color);
+            return;
+        }
+        for graphic_object in graphic_objects {
+            let mut polygon = graphic_object
+                .as_any()
+                .downcast_ref::<Polygon2f>()
+                .unwrap()
+                .clone();
+            polygon.border_color = color;
+            polygon.color = [color[0], color[1], color[2], 0.5];
+            polygon.render(&mut self.canvas);
+        }
+    }
+
+    // images cover cells, transparent pixels are skipped
+    fn render_images(&mut self, width: i32, view_offset: i32) {
+        let height = self.size.1 * self.font_size.1;
+        for ((x0, y0), _, image) in self.screen[self.sid].images().iter() {
+            let x0 = x0 * self.font_size.0;
+            let y0 = (y0 + view_offset) * self.font_size.1;
+            // rows above screen are kept until whole image scrolls out
+            for y in (-y0).max(0)..image.height.min(height - y0) {
+                for x in 0..image.width.min(width - x0) {
+                    if let Some(color) = image.pixels[(x + y * image.width) as usize] {
+                        let index = ((x0 + x + (y0 + y) * width) * 3) as usize;
+                        self.canvas.data[index..index + 3].copy_from_slice(&color);
+                    }
+                }
+            }
+        }
+    }
+
+    // debug text on top right, drawn over screen content
+    fn render_overlay(&mut self, width: i32) {
+        let text = std::mem::take(&mut self.overlay_text);
+        let len = text.len() as i32;
+        let x0 = (self.size.0 - len).max(0);
+        fill_rect(
+            &mut self.canvas,
+            width,
+            (self.font_size.0 * x0, 0),
+            (self.font_size.0 * (self.size.0 - x0), self.font_size.1),
+            [0., 0., 0., 1.],
+        );
+        for (x, ch) in (x0..self.size.0).zip(text.bytes()) {
+            self.draw_glyph(ch, (self.font_size.0 * x, 0), 0., [0., 1., 0., 1.]);
+        }
+        self.overlay_text = text;
+    }
+
+    // unfocused cursor is steady and hollow
+    fn render_cursor(&mut self, cursor: (i32, i32), width: i32, now: std::time::Instant) {
+        if !self.cursor_visible || (self.focused && !self.cursor_on(now)) {
+            return;
+        }
+        let pos = (self.font_size.0 * cursor.0, self.font_size.1 * cursor.1);
+        let (w, h) = self.font_size;
+        // cursor has default colors, dimmed like cells
+        let (fg, bg) = self.resolve_colors(&Attr::default());
+        match (self.cursor_style, self.focused) {
+            (CursorStyle::Block, true) => {
+                fill_rect(&mut self.canvas, width, pos, self.font_size, fg);
+                // glyph under block is drawn inverted to stay visible
+                let cell = self.screen[self.sid].view_cell(cursor.0, cursor.1);
+                if !cell.attr.concealed {
+                    let shear = if cell.attr.italic {
+                        self.italic_shear
+                    } else {
+                        0.
+                    };
+                    self.draw_glyph(cell.ch, pos, shear, bg);
+                }
+            }
+            (CursorStyle::Block, false) => {
+                for (rect_pos, rect_size) in [
+                    ((pos.0, pos.1), (w, 1)),
+                    ((pos.0, pos.1 + h - 1), (w, 1)),
+                    ((pos.0, pos.1), (1, h)),
+                    ((pos.0 + w - 1, pos.1), (1, h)),
+                ]
+                .iter()
+                {
+                    fill_rect(&mut self.canvas, width, *rect_pos, *rect_size, fg);
+                }
+            }
+            (CursorStyle::Underline, focused) => {
+                let thickness = if focused { 2 } else { 1 };
+                fill_rect(
+                    &mut self.canvas,
+                    width,
+                    (pos.0, pos.1 + h - 2),
+                    (w, thickness),
+                    fg,
+                );
+            }
+            (CursorStyle::Bar, focused) => {
+                let ch = b'|';
+                let color = if focused {
+                    fg
+                } else {
+                    [fg[0] * 0.5, fg[1] * 0.5, fg[2] * 0.5, fg[3]]
+                };
+                self.draw_glyph(ch, pos, 0., color);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // feed bytes like pty output, return reports
+    fn feed(console: &mut Console, bytes: &[u8]) -> Vec<u8> {
+        let mut reports = Vec::new();
+        for &ch in bytes {
+            if let Some(report) = console.put_char(ch) {
+                reports.extend(report);
+            }
+        }
+        reports
+    }
+
+    // RGB of canvas pixel
+    fn pixel(console: &Console, pos: (i32, i32)) -> [u8; 3] {
+        let width = console.get_canvas_size().0 as i32;
+        let index = ((pos.0 + pos.1 * width) * 3) as usize;
+        [
+            console.canvas.data[index],
+            console.canvas.data[index + 1],
+            console.canvas.data[index + 2],
+        ]
+    }
+
+    #[test]
+    fn multiline_paste_is_held() {
+        let mut console = Console::new((10, 4));
+        assert_eq!(console.paste("ls"), Some(b"ls".to_vec()));
+        assert_eq!(console.paste("rm -rf x\n"), None);
+        assert!(console
+            .take_title()
+            .unwrap()
+            .starts_with("[paste again to confirm]"));
+        assert_eq!(console.paste("rm -rf x\n"), Some(b"rm -rf x\n".to_vec()));
+        assert!(!console.take_title().unwrap().starts_with("[paste"));
+        console.paste_guard = false;
+        assert_eq!(console.paste("a\nb"), Some(b"a\nb".to_vec()));
+    }
+
+    #[test]
+    fn tmux_passthrough_is_ignored() {
+        let mut console = Console::new((10, 4));
+        feed(&mut console, b"abc");
+        feed(&mut console, b"\x1bPtmux;\x1b\x1b[2Jhello\x1b\\");
+        assert_eq!(console.snapshot(), "abc\n\n\n");
+        assert_eq!(console.get_cursor(), (3, 0));
+    }
+
+    #[test]
+    fn palette_change_redraws_both_screens() {
+        let mut console = Console::new((10, 4));
+        feed(&mut console, b"\x1b[31mred");
+        for screen in console.screen.iter_mut() {
+            screen.take_damage();
+        }
+        feed(&mut console, b"\x1b]4;1;rgb:00/ff/00\x1b\\");
+        assert_eq!(console.palette[1], [0., 1., 0., 1.]);
+        for screen in console.screen.iter_mut() {
+            assert!(screen.take_damage().len() >= 40);
+        }
+    }
+
+    #[test]
+    fn erase_chars_keeps_cursor() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"abcdef\x1b[1;2H\x1b[3X");
+        assert_eq!(console.snapshot(), "a   ef\n");
+        assert_eq!(console.get_cursor(), (1, 0));
+        feed(&mut console, b"\x1b[99X");
+        assert_eq!(console.snapshot(), "a\n");
+    }
+
+    #[test]
+    fn osc7_sets_cwd() {
+        let mut console = Console::new((10, 2));
+        assert_eq!(console.get_cwd(), None);
+        feed(&mut console, b"\x1b]7;file://host/tmp/a%20b\x07");
+        assert_eq!(console.get_cwd(), Some("/tmp/a b"));
+        // invalid url keeps old cwd
+        feed(&mut console, b"\x1b]7;/tmp\x1b\\");
+        assert_eq!(console.get_cwd(), Some("/tmp/a b"));
+    }
+
+    #[test]
+    fn glyph_fallback_for_unsupported_char() {
+        fn fill_cell(
+            canvas: &mut Canvas,
+            width: i32,
+            pos: (i32, i32),
+            size: (i32, i32),
+            _: u8,
+            color: [f32; 4],
+        ) {
+            fill_rect(canvas, width, pos, size, color);
+        }
+        let mut console = Console::new((10, 2));
+        console.glyph_fallback = fill_cell;
+        feed(&mut console, b"\xffa");
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [255, 153, 0]);
+        let font_size = console.get_font_size();
+        assert_eq!(pixel(&console, (font_size.0, 0)), [0, 0, 0]);
+    }
+
+    #[test]
+    fn padding_grows_canvas_not_glyphs() {
+        let mut console = Console::new((10, 2));
+        let scaler = console.scaler;
+        assert_eq!(console.get_canvas_size(), (150, 40));
+        console.set_padding((2, 4));
+        assert_eq!(console.get_font_size(), (17, 24));
+        assert_eq!(console.get_canvas_size(), (170, 48));
+        assert_eq!(console.canvas.data.len(), 170 * 48 * 3);
+        assert_eq!(console.scaler, scaler);
+    }
+
+    #[test]
+    fn bold_is_bright() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[1;31mx");
+        let attr = console.screen[0].view_cell(0, 0).attr;
+        assert_eq!(console.resolve_colors(&attr).0, console.palette[9]);
+        console.bold_is_bright = false;
+        assert_eq!(console.resolve_colors(&attr).0, console.palette[1]);
+    }
+
+    #[test]
+    fn steady_cursor() {
+        let mut console = Console::new((10, 2));
+        assert!(console.cursor_blinking());
+        // blinking cursor is off in second half of each second
+        let epoch = console.blink_epoch;
+        assert!(console.cursor_on(epoch + std::time::Duration::from_millis(300)));
+        assert!(!console.cursor_on(epoch + std::time::Duration::from_millis(600)));
+        console.cursor_blink = false;
+        assert!(!console.cursor_blinking());
+        // steady cursor is always drawn
+        for ms in (0..3000).step_by(100) {
+            assert!(console.cursor_on(epoch + std::time::Duration::from_millis(ms)));
+        }
+        feed(&mut console, b"\x1b[1 q");
+        assert!(console.cursor_blinking());
+        feed(&mut console, b"\x1b[0 q");
+        assert!(!console.cursor_blinking());
+    }
+
+    #[test]
+    fn selection_color_is_rendered() {
+        let mut console = Console::new((10, 2));
+        console.selection_color = [1., 0., 0., 1.];
+        console.start_selection((0, 0));
+        console.extend_selection((1, 0));
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [255, 0, 0]);
+        let font_size = console.get_font_size();
+        assert_eq!(pixel(&console, (font_size.0 * 2, 0)), [0, 0, 0]);
+        console.selection_color = [0., 0., 1., 0.5];
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [0, 0, 127]);
+    }
+
+    #[test]
+    fn clear_to_scrollback() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"a\r\nb\x1b[2J");
+        console.scroll_view(2);
+        assert_eq!(console.screen[0].view_offset(), 0);
+        console.clear_to_scrollback = true;
+        feed(&mut console, b"\x1b[Hc\r\nd\x1b[2J");
+        assert_eq!(console.snapshot(), "\n");
+        console.scroll_view(2);
+        assert_eq!(console.screen[0].view_cell(0, 0).ch, b'c');
+        assert_eq!(console.screen[0].view_cell(0, 1).ch, b'd');
+    }
+
+    #[test]
+    fn blink_stops_when_idle() {
+        let mut console = Console::new((10, 2));
+        console.blink_idle_timeout = std::time::Duration::from_secs(10);
+        let epoch = console.blink_epoch;
+        let later = |ms| epoch + std::time::Duration::from_millis(ms);
+        assert!(!console.cursor_idle(later(9600)));
+        assert!(!console.cursor_on(later(9600)));
+        assert!(console.cursor_idle(later(10600)));
+        assert!(console.cursor_on(later(10600)));
+        // activity restarts blinking
+        console.activity();
+        assert!(!console.cursor_idle(console.blink_epoch + std::time::Duration::from_secs(1)));
+        // zero never stops blinking
+        console.blink_idle_timeout = std::time::Duration::from_secs(0);
+        assert!(!console.cursor_idle(later(10600)));
+    }
+
+    #[test]
+    fn alt_screen_lines_stay_out_of_primary_scrollback() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[?1049h1\r\n2\r\n3\r\n4\x1b[?1049l");
+        console.scroll_view(2);
+        assert_eq!(console.screen[0].view_offset(), 0);
+        // alternate screen has its own
+        console.alt_screen_scrollback = true;
+        feed(&mut console, b"\x1b[?1049h1\r\n2\r\n3\r\n4");
+        console.scroll_view(2);
+        assert_eq!(console.screen[1].view_cell(0, 0).ch, b'1');
+        feed(&mut console, b"\x1b[?1049l");
+        console.scroll_view(2);
+        assert_eq!(console.screen[0].view_offset(), 0);
+    }
+
+    #[test]
+    fn alt_screen_saves_cursor() {
+        let mut console = Console::new((10, 4));
+        feed(&mut console, b"\x1b[2;4H\x1b[?1049h\x1b[1;6H\x1b[?1049l");
+        assert_eq!(console.get_cursor(), (3, 1));
+        // ?1049 overwrites DECSC slot
+        feed(
+            &mut console,
+            b"\x1b[1;1H\x1b7\x1b[2;4H\x1b[?1049h\x1b[?1049l\x1b8",
+        );
+        assert_eq!(console.get_cursor(), (3, 1));
+        console.alt_screen_save_cursor = false;
+        feed(
+            &mut console,
+            b"\x1b[1;1H\x1b7\x1b[2;4H\x1b[?1049h\x1b[?1049l\x1b8",
+        );
+        assert_eq!(console.get_cursor(), (0, 0));
+    }
+
+    #[test]
+    fn lf_keeps_column() {
+        let mut console = Console::new((10, 4));
+        feed(&mut console, b"abc\n");
+        assert_eq!(console.get_cursor(), (3, 1));
+    }
+
+    #[test]
+    fn lf_with_lnm_returns_carriage() {
+        let mut console = Console::new((10, 4));
+        feed(&mut console, b"\x1b[20habc\n\x0b");
+        assert_eq!(console.get_cursor(), (0, 2));
+        feed(&mut console, b"\x1b[20labc\n");
+        assert_eq!(console.get_cursor(), (3, 3));
+    }
+
+    #[test]
+    fn enter_key_with_lnm() {
+        let mut console = Console::new((10, 4));
+        assert_eq!(console.enter_key(false), b"\r");
+        feed(&mut console, b"\x1b[20h");
+        assert_eq!(console.enter_key(false), b"\r\n");
+        feed(&mut console, b"\x1b[20l");
+        assert_eq!(console.enter_key(false), b"\r");
+    }
+
+    #[test]
+    fn copy_scrolled_back_selection() {
+        let mut console = Console::new((10, 4));
+        feed(
+            &mut console,
+            b"old1\r\nold2\r\nnew1\r\nnew2\r\nnew3\r\nnew4",
+        );
+        console.scroll_view(2);
+        console.start_selection((0, 0));
+        console.extend_selection((3, 1));
+        assert_eq!(console.selected_text(false).as_deref(), Some("old1\nold2"));
+    }
+
+    #[test]
+    fn image_partly_above_screen() {
+        let mut console = Console::new((10, 4));
+        console.sixel = true;
+        // 3 rows high, cursor ends on last row,
+        // one band of color 4, two of 2, one of 3, then 1
+        feed(
+            &mut console,
+            b"\x1bPq\"1;1;2;60#4~~-#2~~-~~-#3~~-#1~~-~~-~~-~~-~~-~~\x1b\\",
+        );
+        feed(&mut console, b"\n");
+        console.render();
+        // first 20 pixel rows scrolled above
+        assert_eq!(pixel(&console, (0, 0)), [205, 205, 0]);
+        assert_eq!(pixel(&console, (1, 3)), [205, 205, 0]);
+        assert_eq!(pixel(&console, (0, 4)), [205, 0, 0]);
+        assert_eq!(pixel(&console, (1, 39)), [205, 0, 0]);
+        assert_eq!(pixel(&console, (0, 40)), [0, 0, 0]);
+        assert_eq!(pixel(&console, (2, 0)), [0, 0, 0]);
+        for y in 0..80 {
+            assert_ne!(pixel(&console, (0, y)), [0, 0, 238]);
+            assert_ne!(pixel(&console, (0, y)), [0, 205, 0]);
+        }
+    }
+
+    #[test]
+    fn sixel_image_pixels() {
+        let mut console = Console::new((4, 2));
+        console.sixel = true;
+        // 2x6 pixels of color register 1, then 1x6 of register 2
+        feed(&mut console, b"\x1bPq#1~~#2~\x1b\\");
+        console.render();
+        let pixel = |x: i32, y: i32| {
+            let index = ((x + y * console.canvas.width) * 3) as usize;
+            console.canvas.data[index..index + 3].to_vec()
+        };
+        assert_eq!(pixel(0, 0), vec![205, 0, 0]);
+        assert_eq!(pixel(1, 5), vec![205, 0, 0]);
+        assert_eq!(pixel(2, 3), vec![0, 205, 0]);
+        assert_eq!(pixel(3, 0), vec![0, 0, 0]);
+        assert_eq!(pixel(0, 6), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn reverse_index_in_scroll_region() {
+        let mut console = Console::new((10, 5));
+        feed(
+            &mut console,
+            b"1\r\n2\r\n3\r\n4\r\n5\x1b[2;4r\x1b[2;1H\x1bM",
+        );
+        assert_eq!(console.snapshot(), "1\n\n2\n3\n5");
+        // not at top margin, only moves up
+        feed(&mut console, b"\x1b[4;1H\x1bM");
+        assert_eq!(console.get_cursor(), (0, 2));
+        assert_eq!(console.snapshot(), "1\n\n2\n3\n5");
+    }
+
+    #[test]
+    fn scroll_up_and_down_in_region() {
+        let mut console = Console::new((10, 5));
+        feed(&mut console, b"1\r\n2\r\n3\r\n4\r\n5\x1b[2;4r\x1b[S");
+        assert_eq!(console.snapshot(), "1\n3\n4\n\n5");
+        feed(&mut console, b"\x1b[2T");
+        assert_eq!(console.snapshot(), "1\n\n\n3\n5");
+    }
+
+    #[test]
+    fn cursor_style_cycle_and_default() {
+        let mut console = Console::new((10, 2));
+        console.default_cursor_style = CursorStyle::Underline;
+        for (param, style) in [
+            (b"2", CursorStyle::Block),
+            (b"4", CursorStyle::Underline),
+            (b"6", CursorStyle::Bar),
+            (b"0", CursorStyle::Underline),
+        ]
+        .iter()
+        {
+            feed(&mut console, &[b"\x1b[", &param[..], b" q"].concat());
+            assert_eq!(console.cursor_style, *style);
+        }
+        feed(&mut console, b"\x1b[1 q\x1bc");
+        assert_eq!(console.cursor_style, CursorStyle::Underline);
+        assert_eq!(console.cursor_blinking, None);
+    }
+
+    #[test]
+    fn underline_color() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[4;58;5;1mx\x1b[58:2::1:2:3my\x1b[59mz");
+        let screen = &console.screen[0];
+        assert_eq!(
+            screen.view_cell(0, 0).attr.underline_color,
+            Color::Indexed(1)
+        );
+        assert_eq!(
+            screen.view_cell(1, 0).attr.underline_color,
+            Color::Rgb(1, 2, 3)
+        );
+        assert_eq!(screen.view_cell(2, 0).attr.underline_color, Color::Default);
+        assert_eq!(screen.view_cell(0, 0).attr.fg, Color::Default);
+    }
+
+    #[test]
+    fn underline_styles() {
+        let mut console = Console::new((10, 2));
+        feed(
+            &mut console,
+            b"\x1b[4:3ma\x1b[4:4mb\x1b[4:5mc\x1b[4:0md\x1b[4:2me\x1b[24mf",
+        );
+        let styles: Vec<UnderlineStyle> = (0..6)
+            .map(|x| console.screen[0].view_cell(x, 0).attr.underline)
+            .collect();
+        assert_eq!(
+            styles,
+            vec![
+                UnderlineStyle::Curly,
+                UnderlineStyle::Dotted,
+                UnderlineStyle::Dashed,
+                UnderlineStyle::None,
+                UnderlineStyle::Double,
+                UnderlineStyle::None,
+            ]
+        );
+    }
+
+    #[test]
+    fn decrqm_cursor_visible() {
+        let mut console = Console::new((10, 2));
+        assert_eq!(feed(&mut console, b"\x1b[?25$p"), b"\x1b[?25;1$y");
+        assert_eq!(feed(&mut console, b"\x1b[?25l\x1b[?25$p"), b"\x1b[?25;2$y");
+        // unknown mode
+        assert_eq!(feed(&mut console, b"\x1b[?9999$p"), b"\x1b[?9999;0$y");
+    }
+
+    #[test]
+    fn tab_stop_set_and_clear() {
+        let mut console = Console::new((20, 2));
+        feed(&mut console, b"\x1b[1;4H\x1bH\r\t");
+        assert_eq!(console.get_cursor(), (3, 0));
+        feed(&mut console, b"\t");
+        assert_eq!(console.get_cursor(), (8, 0));
+        // clear stop at cursor
+        feed(&mut console, b"\x1b[1;4H\x1b[0g\r\t");
+        assert_eq!(console.get_cursor(), (8, 0));
+        // clear all, tab goes to last column
+        feed(&mut console, b"\x1b[3g\r\t");
+        assert_eq!(console.get_cursor(), (19, 0));
+    }
+
+    #[test]
+    fn focus_reporting() {
+        let mut console = Console::new((10, 2));
+        assert_eq!(console.focus(false), None);
+        feed(&mut console, b"\x1b[?1004h");
+        assert_eq!(console.focus(true), Some(b"\x1b[I".to_vec()));
+        assert_eq!(console.focus(false), Some(b"\x1b[O".to_vec()));
+        feed(&mut console, b"\x1b[?1004l");
+        assert_eq!(console.focus(true), None);
+    }
+
+    #[test]
+    fn hollow_cursor_when_unfocused() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[2 q");
+        let (w, h) = console.get_font_size();
+        console.render();
+        assert_eq!(pixel(&console, (w / 2, h / 2)), [255, 153, 0]);
+        console.focus(false);
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [255, 153, 0]);
+        assert_eq!(pixel(&console, (w - 1, h - 1)), [255, 153, 0]);
+        assert_eq!(pixel(&console, (w / 2, h / 2)), [0, 0, 0]);
+    }
+
+    #[test]
+    fn save_and_restore_private_modes() {
+        let mut console = Console::new((10, 2));
+        feed(
+            &mut console,
+            b"\x1b[?2004h\x1b[?1004;2004s\x1b[?1004h\x1b[?2004l",
+        );
+        assert_eq!(feed(&mut console, b"\x1b[?1004$p"), b"\x1b[?1004;1$y");
+        feed(&mut console, b"\x1b[?1004;2004r");
+        assert_eq!(feed(&mut console, b"\x1b[?1004$p"), b"\x1b[?1004;2$y");
+        assert_eq!(feed(&mut console, b"\x1b[?2004$p"), b"\x1b[?2004;1$y");
+    }
+
+    #[test]
+    fn report_window_size() {
+        let mut console = Console::new((80, 24));
+        assert_eq!(feed(&mut console, b"\x1b[18t"), b"\x1b[8;24;80t");
+        assert_eq!(feed(&mut console, b"\x1b[14t"), b"\x1b[4;480;1200t");
+        console.resize((100, 30));
+        assert_eq!(feed(&mut console, b"\x1b[18t"), b"\x1b[8;30;100t");
+    }
+
+    #[test]
+    fn synchronized_output() {
+        let mut console = Console::new((10, 2));
+        let now = std::time::Instant::now();
+        assert!(console.should_render(now));
+        feed(&mut console, b"\x1b[?2026h");
+        let start = console.sync_output.unwrap();
+        assert!(!console.should_render(start));
+        assert_eq!(feed(&mut console, b"\x1b[?2026$p"), b"\x1b[?2026;1$y");
+        feed(&mut console, b"\x1b[?2026l");
+        assert!(console.should_render(start));
+        // stuck update is drawn after timeout
+        feed(&mut console, b"\x1b[?2026h");
+        let start = console.sync_output.unwrap();
+        assert!(!console.should_render(start + std::time::Duration::from_millis(150)));
+        assert!(console.should_render(start + std::time::Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn erase_scrollback() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"1\r\n2\r\n3\r\n4");
+        console.scroll_view(2);
+        assert_eq!(console.screen[0].view_offset(), 2);
+        feed(&mut console, b"\x1b[3J");
+        assert_eq!(console.screen[0].view_offset(), 0);
+        console.scroll_view(2);
+        assert_eq!(console.screen[0].view_offset(), 0);
+        // visible screen is kept
+        assert_eq!(console.snapshot(), "3\n4");
+    }
+
+    #[test]
+    fn default_text_is_upright() {
+        let mut console = Console::new((4, 1));
+        feed(&mut console, b"#\x1b[3m#");
+        console.italic_shear = 0.;
+        console.render();
+        let upright = console.canvas.data.clone();
+        console.italic_shear = -0.3;
+        console.render();
+        // only italic cell changes with shear
+        let cell_width = (console.get_font_size().0 * 3) as usize;
+        let row_width = cell_width * 4;
+        for (y, (a, b)) in upright
+            .chunks(row_width)
+            .zip(console.canvas.data.chunks(row_width))
+            .enumerate()
+        {
+            assert_eq!(a[..cell_width], b[..cell_width], "row {}", y);
+        }
+        assert_ne!(upright, console.canvas.data);
+    }
+
+    #[test]
+    fn selective_erase_keeps_protected() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[1\"qab\x1b[0\"qcd\x1b[?2K");
+        assert_eq!(console.snapshot(), "ab\n");
+        feed(&mut console, b"cd\r\nef\x1b[?2J");
+        assert_eq!(console.snapshot(), "ab\n");
+        feed(&mut console, b"\x1b[H\x1b[2K");
+        assert_eq!(console.snapshot(), "\n");
+    }
+
+    #[test]
+    fn scroll_long_line_horizontally() {
+        let mut console = Console::new((5, 2));
+        assert!(!console.scroll_horizontal(8));
+        feed(&mut console, b"\x1b[?7labcdefghij");
+        let row = |console: &Console| -> Vec<u8> {
+            (0..5)
+                .map(|x| console.screen[0].view_cell(x, 0).ch)
+                .collect()
+        };
+        // last column is overwritten like xterm
+        assert_eq!(row(&console), b"abcdj");
+        assert!(console.scroll_horizontal(8));
+        // clamped to end of longest line
+        assert_eq!(row(&console), b"fghij");
+        assert!(console.scroll_horizontal(-8));
+        assert_eq!(row(&console), b"abcdj");
+    }
+
+    #[test]
+    fn zero_count_is_default() {
+        let mut console = Console::new((10, 4));
+        feed(&mut console, b"\x1b[0C");
+        assert_eq!(console.get_cursor(), (1, 0));
+        feed(&mut console, b"\x1b[0B\x1b[0;0H");
+        assert_eq!(console.get_cursor(), (0, 0));
+        // single CUP param is the row
+        feed(&mut console, b"\x1b[3H");
+        assert_eq!(console.get_cursor(), (0, 2));
+    }
+
+    #[test]
+    fn decrqss_margins() {
+        let mut console = Console::new((10, 4));
+        assert_eq!(feed(&mut console, b"\x1bP$qr\x1b\\"), b"\x1bP1$r1;4r\x1b\\");
+        feed(&mut console, b"\x1b[2;3r");
+        assert_eq!(feed(&mut console, b"\x1bP$qr\x1b\\"), b"\x1bP1$r2;3r\x1b\\");
+        assert_eq!(feed(&mut console, b"\x1bP$qx\x1b\\"), b"\x1bP0$r\x1b\\");
+    }
+
+    #[test]
+    fn del_is_ignored() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"ab\x7fc");
+        assert_eq!(console.snapshot(), "abc\n");
+        assert_eq!(console.get_cursor(), (3, 0));
+        feed(&mut console, b"\x7f");
+        assert_eq!(console.snapshot(), "abc\n");
+        assert_eq!(console.get_cursor(), (3, 0));
+    }
+
+    #[test]
+    fn sixel_ignored_when_off() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[?25lab");
+        feed(&mut console, b"\x1bPq#1~~~\x1b\\");
+        feed(&mut console, b"\x1b_Gf=24;AAAA\x1b\\");
+        assert_eq!(console.snapshot(), "ab\n");
+        assert_eq!(console.get_cursor(), (2, 0));
+        console.render();
+        let x = console.font_size.0 * 2;
+        assert_eq!(pixel(&console, (x, 0)), [0, 0, 0]);
+        console.sixel = true;
+        feed(&mut console, b"\x1bPq#1~~~\x1b\\");
+        console.render();
+        assert_eq!(pixel(&console, (x, 0)), [205, 0, 0]);
+    }
+
+    #[test]
+    fn backward_tab() {
+        let mut console = Console::new((30, 2));
+        feed(&mut console, b"\x1b[11G\x1b[Z");
+        assert_eq!(console.get_cursor(), (8, 0));
+        feed(&mut console, b"\x1b[11G\x1b[2Z");
+        assert_eq!(console.get_cursor(), (0, 0));
+        feed(&mut console, b"\x1b[Z");
+        assert_eq!(console.get_cursor(), (0, 0));
+    }
+
+    #[test]
+    fn cht_hpa_vpa() {
+        let mut console = Console::new((30, 5));
+        feed(&mut console, b"\x1b[2I");
+        assert_eq!(console.get_cursor(), (16, 0));
+        feed(&mut console, b"\x1b[5`");
+        assert_eq!(console.get_cursor(), (4, 0));
+        feed(&mut console, b"\x1b[3d");
+        assert_eq!(console.get_cursor(), (4, 2));
+        feed(&mut console, b"\x1b[99`\x1b[99d");
+        assert_eq!(console.get_cursor(), (29, 4));
+        feed(&mut console, b"\x1b[9I");
+        assert_eq!(console.get_cursor(), (29, 4));
+    }
+
+    #[test]
+    fn copy_with_sgr() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"a\x1b[1;31mbc\x1b[0md\r\n\x1b[44me");
+        console.start_selection((0, 0));
+        console.extend_selection((3, 0));
+        assert_eq!(
+            console.selected_text(true).as_deref(),
+            Some("a\x1b[0;1;31mbc\x1b[0md")
+        );
+        assert_eq!(console.selected_text(false).as_deref(), Some("abcd"));
+        console.start_selection((0, 1));
+        console.extend_selection((0, 1));
+        assert_eq!(
+            console.selected_text(true).as_deref(),
+            Some("\x1b[0;44me\x1b[0m")
+        );
+    }
+
+    #[test]
+    fn resolve_color_combos() {
+        let mut console = Console::new((10, 2));
+        let (fg, bg) = (console.default_fg, console.default_bg);
+        let red = console.palette[1];
+        let bright_red = console.palette[9];
+        let attr = |fg, bold, reverse| Attr {
+            fg,
+            bold,
+            reverse,
+            ..Attr::default()
+        };
+        // bold brightens before reverse swaps
+        assert_eq!(
+            console.resolve_colors(&attr(Color::Named(1), true, true)),
+            (bg, bright_red)
+        );
+        assert_eq!(
+            console.resolve_colors(&attr(Color::Default, true, true)),
+            (bg, fg)
+        );
+        // only the 8 named colors are brightened
+        assert_eq!(
+            console.resolve_colors(&attr(Color::Indexed(1), true, false)),
+            (red, bg)
+        );
+        assert_eq!(
+            console.resolve_colors(&attr(Color::Named(9), true, false)),
+            (bright_red, bg)
+        );
+        console.bold_is_bright = false;
+        assert_eq!(
+            console.resolve_colors(&attr(Color::Named(1), true, true)),
+            (bg, red)
+        );
+        console.unfocused_brightness = 0.5;
+        console.focus(false);
+        let (dim_fg, dim_bg) = console.resolve_colors(&attr(Color::Default, false, true));
+        assert_eq!(dim_fg, bg);
+        assert_eq!(dim_bg, [fg[0] * 0.5, fg[1] * 0.5, fg[2] * 0.5, fg[3]]);
+    }
+
+    #[test]
+    fn insert_inside_lr_margins() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"abcdefghij\x1b[?69h\x1b[3;6s\x1b[1;4H\x1b[@");
+        assert_eq!(console.snapshot(), "abc deghij\n");
+        feed(&mut console, b"\x1b[9@");
+        assert_eq!(console.snapshot(), "abc   ghij\n");
+        // delete pulls blanks in at right margin
+        feed(
+            &mut console,
+            b"\x1b[1;1H\x1b[?69l\x1b[2Kabcdefghij\x1b[?69h\x1b[3;6s\x1b[1;3H\x1b[P",
+        );
+        assert_eq!(console.snapshot(), "abdef ghij\n");
+    }
+
+    #[test]
+    fn bell_modes() {
+        let corner = |console: &mut Console| {
+            console.render();
+            let (w, h) = console.get_canvas_size();
+            pixel(console, (w as i32 - 1, h as i32 - 1))
+        };
+        for &mode in [
+            BellMode::Flash,
+            BellMode::Mark,
+            BellMode::Audible,
+            BellMode::Silent,
+        ]
+        .iter()
+        {
+            let mut console = Console::new((10, 2));
+            console.bell_mode = mode;
+            console.focus(false);
+            console.take_title();
+            feed(&mut console, b"\x07");
+            assert_eq!(corner(&mut console) != [0, 0, 0], mode == BellMode::Flash);
+            let title = console.take_title();
+            assert_eq!(title.is_some(), mode == BellMode::Mark, "{:?}", mode);
+            if mode == BellMode::Mark {
+                assert!(title.unwrap().starts_with("[bell]"));
+                // only marked once
+                feed(&mut console, b"\x07");
+                assert_eq!(console.take_title(), None);
+                console.focus(true);
+                assert!(!console.take_title().unwrap().starts_with("[bell]"));
+            }
+        }
+    }
+
+    #[test]
+    fn cr_overwrites_from_line_start() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"abcdef\rXYZ");
+        assert_eq!(console.snapshot(), "XYZdef\n");
+        assert_eq!(console.get_cursor(), (3, 0));
+    }
+
+    #[test]
+    fn keypad_enter_key() {
+        let mut console = Console::new((10, 2));
+        assert_eq!(console.enter_key(true), b"\r");
+        feed(&mut console, b"\x1b=");
+        assert_eq!(console.enter_key(true), b"\x1bOM");
+        assert_eq!(console.enter_key(false), b"\r");
+        feed(&mut console, b"\x1b[20h");
+        assert_eq!(console.enter_key(true), b"\x1bOM");
+        feed(&mut console, b"\x1b>");
+        assert_eq!(console.enter_key(true), b"\r\n");
+    }
+
+    #[test]
+    fn erase_line_with_blue_bg() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"ab\x1b[?25l\x1b[44m\x1b[K");
+        assert_eq!(console.screen[0].view_cell(5, 0).attr.bg, Color::Named(4));
+        assert_eq!(console.screen[0].view_cell(5, 1).attr.bg, Color::Default);
+        console.render();
+        let x = console.font_size.0 * 5;
+        assert_eq!(pixel(&console, (x, 0)), [0, 0, 238]);
+        assert_eq!(pixel(&console, (x, console.font_size.1)), [0, 0, 0]);
+    }
+
+    #[test]
+    fn bce_on_and_off() {
+        for &bce in [true, false].iter() {
+            let mut console = Console::new((10, 3));
+            console.set_bce(bce);
+            let red = if bce { Color::Named(1) } else { Color::Default };
+            let bg_at = |console: &Console, x, y| console.screen[0].view_cell(x, y).attr.bg;
+            feed(&mut console, b"\x1b[41m\x1b[2@");
+            assert_eq!(bg_at(&console, 1, 0), red);
+            assert_eq!(bg_at(&console, 5, 0), Color::Default);
+            // new line from scroll down
+            feed(&mut console, b"\x1b[T");
+            assert_eq!(bg_at(&console, 5, 0), red);
+            assert_eq!(bg_at(&console, 1, 1), red);
+            feed(&mut console, b"\x1b[3;1H\x1b[K");
+            assert_eq!(bg_at(&console, 9, 2), red);
+        }
+    }
+
+    #[test]
+    fn title_set_and_report() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b]2;vim a.txt\x07");
+        assert_eq!(console.take_title().as_deref(), Some("vim a.txt"));
+        assert_eq!(feed(&mut console, b"\x1b[21t"), b"\x1b]lvim a.txt\x1b\\");
+        // icon title is separate
+        feed(&mut console, b"\x1b]1;icon\x1b\\");
+        assert_eq!(feed(&mut console, b"\x1b[21t"), b"\x1b]lvim a.txt\x1b\\");
+        assert_eq!(feed(&mut console, b"\x1b[20t"), b"\x1b]Licon\x1b\\");
+        feed(&mut console, b"\x1b]2;\x07");
+        assert_eq!(feed(&mut console, b"\x1b[21t"), b"\x1b]l\x1b\\");
+    }
+
+    #[test]
+    fn dim_when_unfocused() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[?25l\x1b[41m \x1b[0m");
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [205, 0, 0]);
+        // default brightness keeps colors
+        console.focus(false);
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [205, 0, 0]);
+        console.unfocused_brightness = 0.5;
+        console.mark_all_dirty();
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [102, 0, 0]);
+        console.focus(true);
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [205, 0, 0]);
+    }
+
+    #[test]
+    fn decscusr_blink() {
+        let mut console = Console::new((10, 2));
+        console.cursor_blink = false;
+        feed(&mut console, b"\x1b[1 q");
+        assert!(console.cursor_blinking());
+        assert_eq!(console.cursor_style, CursorStyle::Block);
+        feed(&mut console, b"\x1b[2 q");
+        assert!(!console.cursor_blinking());
+        assert_eq!(console.cursor_style, CursorStyle::Block);
+        feed(&mut console, b"\x1b[5 q");
+        assert!(console.cursor_blinking());
+        // back to configured steady cursor
+        feed(&mut console, b"\x1b[0 q");
+        assert!(!console.cursor_blinking());
+    }
+
+    #[test]
+    fn locator_report() {
+        let mut console = Console::new((10, 4));
+        assert_eq!(feed(&mut console, b"\x1b['|"), b"\x1b[0&w");
+        console.set_pointer(Some((4, 2)));
+        assert_eq!(feed(&mut console, b"\x1b['|"), b"\x1b[1;0;3;5;1&w");
+        console.set_pointer(None);
+        assert_eq!(feed(&mut console, b"\x1b[1'|"), b"\x1b[0&w");
+    }
+
+    #[test]
+    fn cursor_position_report_is_one_based() {
+        let mut console = Console::new((10, 4));
+        assert_eq!(feed(&mut console, b"\x1b[6n"), b"\x1b[1;1R");
+        assert_eq!(feed(&mut console, b"\x1b[3;7H\x1b[6n"), b"\x1b[3;7R");
+    }
+
+    #[test]
+    fn bright_sgr_colors() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[?25l\x1b[91;102m \x1b[0m");
+        let attr = console.screen[0].view_cell(0, 0).attr;
+        assert_eq!((attr.fg, attr.bg), (Color::Named(9), Color::Named(10)));
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [0, 255, 0]);
+        assert_eq!(attr.sgr_params(), "0;91;102");
+    }
+
+    #[test]
+    fn default_fg_keeps_bold() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[1;31ma\x1b[39mb\x1b[49mc");
+        let attr = console.screen[0].view_cell(1, 0).attr;
+        assert!(attr.bold);
+        assert_eq!(attr.fg, Color::Default);
+        assert!(console.screen[0].view_cell(2, 0).attr.bold);
+    }
+
+    #[test]
+    fn concealed_glyph_not_drawn() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[?25l!\x1b[8m!\x1b[0m");
+        console.render();
+        let w = console.font_size.0;
+        let center = (w * 7 / 20, console.font_size.1 / 2);
+        assert_eq!(pixel(&console, center), [255, 153, 0]);
+        assert_eq!(pixel(&console, (center.0 + w, center.1)), [0, 0, 0]);
+        console.start_selection((0, 0));
+        console.extend_selection((1, 0));
+        assert_eq!(console.selected_text(false).as_deref(), Some("!!"));
+    }
+
+    #[test]
+    fn tab_width_four() {
+        let mut console = Console::new((20, 2));
+        console.set_tab_width(4);
+        feed(&mut console, b"a\t");
+        assert_eq!(console.get_cursor(), (4, 0));
+        feed(&mut console, b"\t");
+        assert_eq!(console.get_cursor(), (8, 0));
+        // kept by reset
+        feed(&mut console, b"\x1bc\t");
+        assert_eq!(console.get_cursor(), (4, 0));
+    }
+
+    #[test]
+    fn s7c1t_and_s8c1t_consumed() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"a\x1b Fb\x1b Gc");
+        assert_eq!(console.snapshot(), "abc\n");
+        // replies stay 7-bit
+        assert_eq!(feed(&mut console, b"\x1b[6n"), b"\x1b[1;4R");
+    }
+
+    #[test]
+    fn glyph_under_block_cursor() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[2 q!\x1b[D");
+        console.render();
+        let center = (console.font_size.0 * 7 / 20, console.font_size.1 / 2);
+        assert_eq!(pixel(&console, (0, 0)), [255, 153, 0]);
+        assert_eq!(pixel(&console, center), [0, 0, 0]);
+        // concealed glyph stays hidden under cursor
+        feed(&mut console, b"\x1b[8m!\x1b[D");
+        console.render();
+        assert_eq!(pixel(&console, center), [255, 153, 0]);
+    }
+
+    #[test]
+    fn selection_follows_scroll() {
+        let mut console = Console::new((10, 3));
+        feed(&mut console, b"a\r\nb\r\nc");
+        console.start_selection((0, 1));
+        console.extend_selection((0, 2));
+        feed(&mut console, b"\r\nd");
+        assert_eq!(console.selected_text(false).as_deref(), Some("b\nc"));
+        feed(&mut console, b"\r\ne");
+        assert_eq!(console.selected_text(false).as_deref(), Some("c"));
+        feed(&mut console, b"\r\nf");
+        assert_eq!(console.selected_text(false), None);
+    }
+
+    #[test]
+    fn modify_other_keys_level_two() {
+        let mut console = Console::new((10, 2));
+        assert_eq!(feed(&mut console, b"\x1b[?4m"), b"\x1b[>4;0m");
+        assert_eq!(console.modify_other_key(b'a', false, true), None);
+        feed(&mut console, b"\x1b[>4;2m");
+        assert_eq!(feed(&mut console, b"\x1b[?4m"), b"\x1b[>4;2m");
+        // level 2 encodes even unambiguous combos
+        assert_eq!(
+            console.modify_other_key(b'a', false, true),
+            Some(b"\x1b[97;5u".to_vec())
+        );
+        // other resources are kept
+        assert_eq!(feed(&mut console, b"\x1b[?1m"), b"\x1b[>1;2m");
+        feed(&mut console, b"\x1b[>4m");
+        assert_eq!(feed(&mut console, b"\x1b[?4m"), b"\x1b[>4;0m");
+    }
+
+    #[test]
+    fn insert_mode_shifts_right() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"abcdefghij\x1b[1;3H\x1b[4hXY");
+        assert_eq!(console.snapshot(), "abXYcdefgh\n");
+        assert_eq!(console.get_cursor(), (4, 0));
+        feed(&mut console, b"\x1b[4lZ");
+        assert_eq!(console.snapshot(), "abXYZdefgh\n");
+    }
+
+    #[test]
+    fn hyperlink_cells() {
+        let mut console = Console::new((20, 2));
+        feed(
+            &mut console,
+            b"a\x1b]8;;https://a.example\x1b\\link\x1b]8;;\x1b\\b\x1b]8;id=x;https://b.example\x07c",
+        );
+        assert_eq!(console.snapshot(), "alinkbc\n");
+        assert_eq!(console.link_at((0, 0)), None);
+        assert_eq!(console.link_at((1, 0)), Some("https://a.example"));
+        assert_eq!(console.link_at((4, 0)), Some("https://a.example"));
+        assert_eq!(console.link_at((5, 0)), None);
+        assert_eq!(console.link_at((6, 0)), Some("https://b.example"));
+        assert_eq!(console.link_at((6, 1)), None);
+    }
+
+    #[test]
+    fn overline_on_and_off() {
+        let mut console = Console::new((10, 2));
+        feed(&mut console, b"\x1b[?25l\x1b[53ma\x1b[55mb");
+        assert!(console.screen[0].view_cell(0, 0).attr.overline);
+        assert!(!console.screen[0].view_cell(1, 0).attr.overline);
+        console.render();
+        let w = console.font_size.0;
+        assert_eq!(pixel(&console, (w / 2, 0)), [255, 153, 0]);
+        assert_eq!(pixel(&console, (w + w / 2, 0)), [0, 0, 0]);
+    }
+
+    #[test]
+    fn resize_on_alt_screen_reflows_primary() {
+        let mut console = Console::new((10, 4));
+        feed(&mut console, b"abcdefg\r\nxy\x1b[?1049h\x1b[2Jvim");
+        console.resize((4, 6));
+        feed(&mut console, b"\x1b[?1049l");
+        assert_eq!(console.snapshot(), "abcd\nefg\nxy\n\n\n");
+        console.resize((10, 4));
+        assert_eq!(console.snapshot(), "abcdefg\nxy\n\n");
+    }
+
+    #[test]
+    fn palette_change_invalidates_render_cache() {
+        let mut console = Console::new((4, 2));
+        feed(&mut console, b"\x1b[?25l\x1b[41m \x1b[m");
+        console.render();
+        assert_eq!(pixel(&console, (2, 2)), [205, 0, 0]);
+        let generation = console.cache_generation;
+        feed(&mut console, b"\x1b]4;1;rgb:00/ff/00\x1b\\");
+        assert!(console.cache_generation > generation);
+        console.render();
+        assert_eq!(pixel(&console, (2, 2)), [0, 255, 0]);
+        feed(&mut console, b"\x1b]11;rgb:00/00/ff\x1b\\");
+        console.render();
+        assert_eq!(pixel(&console, (2, 22)), [0, 0, 255]);
+        console.set_padding((2, 2));
+        console.render();
+        assert_eq!(pixel(&console, (2, 2)), [0, 255, 0]);
+        assert_eq!(pixel(&console, (2, 24)), [0, 0, 255]);
+    }
+
+    #[test]
+    fn unchanged_rows_are_reused_until_invalidated() {
+        let mut console = Console::new((4, 2));
+        feed(&mut console, b"\x1b[?25l\x1b[41m \x1b[m");
+        console.render();
+        // no setter, cached row is stale until generation is bumped
+        console.palette[1] = [0., 1., 0., 1.];
+        console.render();
+        assert_eq!(pixel(&console, (2, 2)), [205, 0, 0]);
+        console.invalidate_cache();
+        console.render();
+        assert_eq!(pixel(&console, (2, 2)), [0, 255, 0]);
+        // changed cells are redrawn without a bump
+        feed(&mut console, b"\x1b[H\x1b[44m \x1b[m");
+        console.render();
+        assert_eq!(pixel(&console, (2, 2)), [0, 0, 238]);
+    }
+
+    #[test]
+    fn cursor_on_dim_cell() {
+        let mut console = Console::new((4, 2));
+        feed(
+            &mut console,
+            b"\x1b]11;rgb:ff/ff/ff\x1b\\\x1b[2 q\x1b[31ma\x1b[m\x1b[H",
+        );
+        console.unfocused_brightness = 0.5;
+        console.focus(false);
+        console.render();
+        // hollow cursor, its cell and the empty cells are dimmed alike
+        assert_eq!(pixel(&console, (0, 0)), [127, 76, 0]);
+        assert_eq!(pixel(&console, (2, 2)), [127, 127, 127]);
+        let font_size = console.get_font_size();
+        assert_eq!(pixel(&console, (font_size.0 * 3 + 2, 2)), [127, 127, 127]);
+        console.focus(true);
+        console.render();
+        assert_eq!(pixel(&console, (0, 0)), [255, 153, 0]);
+        assert_eq!(pixel(&console, (font_size.0 * 3 + 2, 2)), [255, 255, 255]);
     }
 }
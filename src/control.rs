@@ -0,0 +1,153 @@
+// unix socket for automation, one command per line:
+// screen, cursor, size, modes, cwd, input TEXT, type TEXT
+// input sends raw bytes, type sends newlines like Enter key
+// replies are length of data in bytes, newline, then data
+
+use crate::console::Console;
+
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+pub struct ControlSocket {
+    path: String,
+    listener: UnixListener,
+    // stream and unfinished command
+    clients: Vec<(UnixStream, Vec<u8>)>,
+}
+
+// \n, \r, \e and \\ in input text
+fn unescape(text: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut iter = text.iter();
+    while let Some(&ch) = iter.next() {
+        if ch != b'\\' {
+            result.push(ch);
+            continue;
+        }
+        match iter.next() {
+            Some(b'n') => result.push(b'\n'),
+            Some(b'r') => result.push(b'\r'),
+            Some(b'e') => result.push(27),
+            Some(&ch) => result.push(ch),
+            None => result.push(b'\\'),
+        }
+    }
+    result
+}
+
+impl ControlSocket {
+    // only owner can connect
+    pub fn bind(path: &str) -> std::io::Result<ControlSocket> {
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(ControlSocket {
+            path: path.to_string(),
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    fn command(line: &[u8], console: &Console, pty_out: &mut Vec<u8>) -> String {
+        let (command, arg) = match line.iter().position(|&ch| ch == b' ') {
+            Some(pos) => (&line[..pos], &line[pos + 1..]),
+            None => (line, &line[line.len()..]),
+        };
+        match command {
+            b"screen" => console.snapshot(),
+            b"cursor" => {
+                let cursor = console.get_cursor();
+                format!("{} {}", cursor.0, cursor.1)
+            }
+            b"size" => {
+                let size = console.get_size();
+                format!("{} {}", size.0, size.1)
+            }
+            b"modes" => console.modes(),
+            // empty if shell does not report it
+            b"cwd" => console.get_cwd().unwrap_or_default().to_string(),
+            b"input" => {
+                pty_out.extend(unescape(arg));
+                String::new()
+            }
+            b"type" => {
+                pty_out.extend(console.typed_bytes(&unescape(arg)));
+                String::new()
+            }
+            _ => format!("unknown command {:?}", String::from_utf8_lossy(command)),
+        }
+    }
+
+    // accept clients and answer complete commands, never blocks
+    pub fn poll(&mut self, console: &Console, pty_out: &mut Vec<u8>) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_ok() {
+                        self.clients.push((stream, Vec::new()));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Control socket accept failed: {}", e);
+                    break;
+                }
+            }
+        }
+        self.clients.retain_mut(|(stream, pending)| {
+            let mut buf = [0; 1024];
+            loop {
+                match stream.read(&mut buf) {
+                    // closed by client
+                    Ok(0) => return false,
+                    Ok(n) => pending.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => return false,
+                }
+            }
+            while let Some(pos) = pending.iter().position(|&ch| ch == b'\n') {
+                let line: Vec<u8> = pending.drain(..=pos).collect();
+                let reply = ControlSocket::command(&line[..pos], console, pty_out);
+                let reply = format!("{}\n{}", reply.len(), reply);
+                // replies are small, drop client if it does not read
+                if stream.write_all(reply.as_bytes()).is_err() {
+                    return false;
+                }
+            }
+            true
+        });
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn screen_dump_over_socket() {
+        let path = std::env::temp_dir().join(format!("fsdterm-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut control = ControlSocket::bind(path).unwrap();
+        let mut console = Console::new((10, 2));
+        for &ch in b"hello\r\nworld" {
+            console.put_char(ch);
+        }
+        let mut client = UnixStream::connect(path).unwrap();
+        client.write_all(b"screen\ninput ls\\n\ncursor\n").unwrap();
+        let mut pty_out = Vec::new();
+        control.poll(&console, &mut pty_out);
+        assert_eq!(pty_out, b"ls\n");
+        drop(control);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "11\nhello\nworld0\n3\n5 1");
+        assert!(UnixStream::connect(path).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+// sdl key events to pty bytes and hotkeys
+
+use crate::console::Console;
+
+use sdl2::keyboard::Keycode;
+
+// what main loop does for a key press besides console changes
+#[derive(Debug, PartialEq)]
+pub enum KeyAction {
+    // bytes for pty
+    Input(Vec<u8>),
+    // text for clipboard
+    Copy(String),
+    // clipboard is pasted
+    Paste,
+    // BREAK for serial consoles behind pty
+    Break,
+}
+
+// modifiers held down
+#[derive(Default)]
+pub struct Keyboard {
+    pub shift: bool,
+    pub ctrl: bool,
+}
+
+fn set_shift(mut ch: u8, shift: bool) -> u8 {
+    if !shift {
+        return ch;
+    }
+    ch = match ch {
+        b'a'..=b'z' => ch - b'a' + b'A',
+        b'1' => b'!',
+        b'2' => b'@',
+        b'3' => b'#',
+        b'4' => b'$',
+        b'5' => b'%',
+        b'6' => b'^',
+        b'7' => b'&',
+        b'8' => b'*',
+        b'9' => b'(',
+        b'0' => b')',
+        b'-' => b'_',
+        b'=' => b'+',
+        b'`' => b'~',
+        b',' => b'<',
+        b'.' => b'>',
+        b'/' => b'?',
+        b'[' => b'{',
+        b']' => b'}',
+        b'\\' => b'|',
+        b';' => b':',
+        _ => ch,
+    };
+    ch
+}
+
+// C0 control of ctrl + key after shift is applied, like xterm
+fn ctrl_byte(ch: u8) -> u8 {
+    match ch {
+        b'a'..=b'z' => ch - b'a' + 1,
+        b'A'..=b'Z' => ch - b'A' + 1,
+        b'@' | b'2' | b' ' => 0,
+        b'[' | b'3' => 27,
+        b'\\' | b'4' => 28,
+        b']' | b'5' => 29,
+        b'^' | b'6' => 30,
+        b'_' | b'/' | b'7' => 31,
+        b'?' | b'8' => 0x7f,
+        _ => ch,
+    }
+}
+
+impl Keyboard {
+    // hotkeys first, then bytes of key with modifiers
+    pub fn key_down(
+        &mut self,
+        console: &mut Console,
+        code: Option<Keycode>,
+        repeat: bool,
+    ) -> Option<KeyAction> {
+        let (shift, ctrl) = (self.shift, self.ctrl);
+        match code {
+            Some(Keycode::F12) => {
+                console.show_overlay = !console.show_overlay;
+                return None;
+            }
+            // copy selection with colors
+            Some(Keycode::C) if ctrl && shift => {
+                return console.selected_text(true).map(KeyAction::Copy);
+            }
+            Some(Keycode::B) if ctrl && shift => return Some(KeyAction::Break),
+            // like ESC c, nothing is sent to shell
+            Some(Keycode::R) if ctrl && shift => {
+                console.reset();
+                return None;
+            }
+            // copy output of last command
+            Some(Keycode::O) if ctrl && shift => {
+                return console.last_output().map(KeyAction::Copy);
+            }
+            Some(Keycode::PageUp) if shift => {
+                console.scroll_view(console.get_size().1);
+                return None;
+            }
+            Some(Keycode::PageDown) if shift => {
+                console.scroll_view(-console.get_size().1);
+                return None;
+            }
+            // only when there are lines wider than screen
+            Some(Keycode::Left) if shift && console.scroll_horizontal(-8) => return None,
+            Some(Keycode::Right) if shift && console.scroll_horizontal(8) => return None,
+            Some(Keycode::Up) if ctrl && shift => {
+                console.jump_prompt(false);
+                return None;
+            }
+            Some(Keycode::Down) if ctrl && shift => {
+                console.jump_prompt(true);
+                return None;
+            }
+            Some(Keycode::Insert) if shift => return Some(KeyAction::Paste),
+            _ => {}
+        }
+        if repeat && !console.auto_repeat() {
+            return None;
+        }
+        console.activity();
+        let mut ch = match code {
+            Some(Keycode::A) => Some(vec![b'a']),
+            Some(Keycode::B) => Some(vec![b'b']),
+            Some(Keycode::C) => Some(vec![b'c']),
+            Some(Keycode::D) => Some(vec![b'd']),
+            Some(Keycode::E) => Some(vec![b'e']),
+            Some(Keycode::F) => Some(vec![b'f']),
+            Some(Keycode::G) => Some(vec![b'g']),
+            Some(Keycode::H) => Some(vec![b'h']),
+            Some(Keycode::I) => Some(vec![b'i']),
+            Some(Keycode::J) => Some(vec![b'j']),
+            Some(Keycode::K) => Some(vec![b'k']),
+            Some(Keycode::L) => Some(vec![b'l']),
+            Some(Keycode::M) => Some(vec![b'm']),
+            Some(Keycode::N) => Some(vec![b'n']),
+            Some(Keycode::O) => Some(vec![b'o']),
+            Some(Keycode::P) => Some(vec![b'p']),
+            Some(Keycode::Q) => Some(vec![b'q']),
+            Some(Keycode::R) => Some(vec![b'r']),
+            Some(Keycode::S) => Some(vec![b's']),
+            Some(Keycode::T) => Some(vec![b't']),
+            Some(Keycode::U) => Some(vec![b'u']),
+            Some(Keycode::V) => Some(vec![b'v']),
+            Some(Keycode::W) => Some(vec![b'w']),
+            Some(Keycode::X) => Some(vec![b'x']),
+            Some(Keycode::Y) => Some(vec![b'y']),
+            Some(Keycode::Z) => Some(vec![b'z']),
+            Some(Keycode::Quote) => Some(vec![b'\'']),
+            Some(Keycode::Comma) => Some(vec![b',']),
+            Some(Keycode::Minus) => Some(vec![b'-']),
+            Some(Keycode::Period) => Some(vec![b'.']),
+            Some(Keycode::Slash) => Some(vec![b'/']),
+            Some(Keycode::Num0) => Some(vec![b'0']),
+            Some(Keycode::Num1) => Some(vec![b'1']),
+            Some(Keycode::Num2) => Some(vec![b'2']),
+            Some(Keycode::Num3) => Some(vec![b'3']),
+            Some(Keycode::Num4) => Some(vec![b'4']),
+            Some(Keycode::Num5) => Some(vec![b'5']),
+            Some(Keycode::Num6) => Some(vec![b'6']),
+            Some(Keycode::Num7) => Some(vec![b'7']),
+            Some(Keycode::Num8) => Some(vec![b'8']),
+            Some(Keycode::Num9) => Some(vec![b'9']),
+            Some(Keycode::Semicolon) => Some(vec![b';']),
+            Some(Keycode::Equals) => Some(vec![b'=']),
+            Some(Keycode::LeftBracket) => Some(vec![b'[']),
+            Some(Keycode::RightBracket) => Some(vec![b']']),
+            Some(Keycode::Backslash) => Some(vec![b'\\']),
+            Some(Keycode::Backquote) => Some(vec![b'`']),
+            Some(Keycode::Backspace) => Some(vec![0x7f]),
+            Some(Keycode::Delete) => Some(vec![27, b'[', b'3', b'~']),
+            Some(Keycode::Escape) => Some(vec![27]),
+            Some(Keycode::Space) => Some(vec![b' ']),
+            Some(Keycode::LShift) | Some(Keycode::RShift) => {
+                self.shift = true;
+                None
+            }
+            Some(Keycode::LCtrl) | Some(Keycode::RCtrl) => {
+                self.ctrl = true;
+                None
+            }
+            Some(Keycode::Return) => Some(console.enter_key(false)),
+            Some(Keycode::KpEnter) => Some(console.enter_key(true)),
+            Some(Keycode::Left) => Some(console.cursor_key(b'D', shift, ctrl)),
+            Some(Keycode::Right) => Some(console.cursor_key(b'C', shift, ctrl)),
+            Some(Keycode::Down) => Some(console.cursor_key(b'B', shift, ctrl)),
+            Some(Keycode::Up) => Some(console.cursor_key(b'A', shift, ctrl)),
+            _ => None,
+        };
+
+        let modified = match &ch {
+            Some(c) if c.len() == 1 => console.modify_other_key(c[0], shift, ctrl),
+            _ => None,
+        };
+
+        // sequences already carry modifiers
+        if let Some(c) = ch.as_mut() {
+            if c.len() == 1 {
+                c[0] = set_shift(c[0], shift);
+            }
+        }
+
+        if modified.is_some() {
+            ch = modified;
+        } else if ctrl {
+            if let Some(c) = ch.as_mut() {
+                if c.len() == 1 {
+                    c[0] = ctrl_byte(c[0]);
+                }
+            }
+        }
+        let ch = ch?;
+        console.cancel_paste();
+        console.clear_selection();
+        console.scroll_to_bottom();
+        Some(KeyAction::Input(ch))
+    }
+
+    pub fn key_up(&mut self, code: Option<Keycode>) {
+        match code {
+            Some(Keycode::LShift) | Some(Keycode::RShift) => self.shift = false,
+            Some(Keycode::LCtrl) | Some(Keycode::RCtrl) => self.ctrl = false,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(keyboard: &mut Keyboard, console: &mut Console, code: Keycode) -> Option<KeyAction> {
+        keyboard.key_down(console, Some(code), false)
+    }
+
+    #[test]
+    fn backspace_and_delete() {
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard::default();
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::Backspace),
+            Some(KeyAction::Input(vec![0x7f]))
+        );
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::Delete),
+            Some(KeyAction::Input(b"\x1b[3~".to_vec()))
+        );
+    }
+
+    #[test]
+    fn repeat_dropped_without_decarm() {
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard::default();
+        let a = Some(KeyAction::Input(b"a".to_vec()));
+        assert_eq!(keyboard.key_down(&mut console, Some(Keycode::A), true), a);
+        for &ch in b"\x1b[?8l" {
+            console.put_char(ch);
+        }
+        assert_eq!(
+            keyboard.key_down(&mut console, Some(Keycode::A), true),
+            None
+        );
+        assert_eq!(keyboard.key_down(&mut console, Some(Keycode::A), false), a);
+    }
+
+    #[test]
+    fn ctrl_shift_letter_with_modify_other_keys() {
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard::default();
+        press(&mut keyboard, &mut console, Keycode::LCtrl);
+        press(&mut keyboard, &mut console, Keycode::LShift);
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::A),
+            Some(KeyAction::Input(vec![1]))
+        );
+        for &ch in b"\x1b[>4;1m" {
+            console.put_char(ch);
+        }
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::A),
+            Some(KeyAction::Input(b"\x1b[97;6u".to_vec()))
+        );
+        // plain ctrl is not ambiguous at level 1
+        keyboard.key_up(Some(Keycode::LShift));
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::A),
+            Some(KeyAction::Input(vec![1]))
+        );
+    }
+
+    #[test]
+    fn f12_toggles_overlay() {
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard::default();
+        assert_eq!(press(&mut keyboard, &mut console, Keycode::F12), None);
+        assert!(console.show_overlay);
+        console.set_overlay_text(String::from("1ms"));
+        console.render();
+        let with_overlay = console.canvas.data.clone();
+        press(&mut keyboard, &mut console, Keycode::F12);
+        assert!(!console.show_overlay);
+        console.render();
+        assert_ne!(with_overlay, console.canvas.data);
+    }
+
+    #[test]
+    fn ctrl_symbols() {
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard::default();
+        let table = [
+            (Keycode::Num2, false, 0),
+            (Keycode::Num2, true, 0),
+            (Keycode::Num3, false, 27),
+            (Keycode::LeftBracket, false, 27),
+            (Keycode::Num4, false, 28),
+            (Keycode::Backslash, false, 28),
+            (Keycode::Num5, false, 29),
+            (Keycode::RightBracket, false, 29),
+            (Keycode::Num6, false, 30),
+            (Keycode::Num6, true, 30),
+            (Keycode::Num7, false, 31),
+            (Keycode::Slash, false, 31),
+            (Keycode::Minus, true, 31),
+            (Keycode::Num8, false, 0x7f),
+            (Keycode::Slash, true, 0x7f),
+        ];
+        keyboard.ctrl = true;
+        for &(code, shift, byte) in table.iter() {
+            keyboard.shift = shift;
+            assert_eq!(
+                press(&mut keyboard, &mut console, code),
+                Some(KeyAction::Input(vec![byte])),
+                "{:?} shift {}",
+                code,
+                shift
+            );
+        }
+    }
+
+    #[test]
+    fn ctrl_space_is_nul() {
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard {
+            ctrl: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::Space),
+            Some(KeyAction::Input(vec![0]))
+        );
+    }
+
+    #[test]
+    fn ctrl_shift_b_sends_break() {
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard {
+            ctrl: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::B),
+            Some(KeyAction::Input(vec![2]))
+        );
+        keyboard.shift = true;
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::B),
+            Some(KeyAction::Break)
+        );
+    }
+
+    #[test]
+    fn ctrl_shift_r_resets() {
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard {
+            ctrl: true,
+            shift: true,
+        };
+        for &ch in b"\x1b[31mabc\x1b[?25l\x1b[?1h" {
+            console.put_char(ch);
+        }
+        assert_eq!(press(&mut keyboard, &mut console, Keycode::R), None);
+        assert_eq!(console.snapshot(), "\n");
+        assert_eq!(console.get_cursor(), (0, 0));
+        assert_eq!(console.modes(), Console::new((10, 2)).modes());
+        keyboard.shift = false;
+        assert_eq!(
+            press(&mut keyboard, &mut console, Keycode::R),
+            Some(KeyAction::Input(vec![0x12]))
+        );
+    }
+
+    #[test]
+    fn enter_follows_option() {
+        use crate::console::EnterKey;
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard::default();
+        for &(option, bytes) in [
+            (EnterKey::Cr, &b"\r"[..]),
+            (EnterKey::Lf, &b"\n"[..]),
+            (EnterKey::CrLf, &b"\r\n"[..]),
+        ]
+        .iter()
+        {
+            console.enter_key = option;
+            assert_eq!(
+                press(&mut keyboard, &mut console, Keycode::Return),
+                Some(KeyAction::Input(bytes.to_vec()))
+            );
+        }
+        assert_eq!(console.typed_bytes(b"a\nb"), b"a\r\nb");
+    }
+}
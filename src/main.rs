@@ -1,52 +1,57 @@
+mod cell;
 mod console;
+mod control;
+mod keyboard;
+mod parser;
+mod renderer;
 mod screen_buffer;
+mod sixel;
+mod title;
 
+extern crate image;
 extern crate mray;
 extern crate nix;
 extern crate sdl2;
 
-use console::Console;
+use console::{parse_color_spec, BellMode, Console, EnterKey};
+use control::ControlSocket;
+use keyboard::{KeyAction, Keyboard};
+use renderer::{letterbox, PngRenderer, Renderer, SdlRenderer};
 
-use nix::fcntl::{open, OFlag};
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
 use nix::pty::{grantpt, posix_openpt, ptsname, unlockpt};
 use nix::sys::stat::Mode;
 use nix::unistd;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::mouse::MouseButton;
 
+use std::ffi::CString;
+use std::io::{ErrorKind, Write};
 use std::os::unix::io::RawFd;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 
-fn set_shift(mut ch: u8, shift: bool) -> u8 {
-    if !shift {
-        return ch;
-    }
-    ch = match ch {
-        b'a'..=b'z' => ch - b'a' + b'A',
-        b'1' => b'!',
-        b'2' => b'@',
-        b'3' => b'#',
-        b'4' => b'$',
-        b'5' => b'%',
-        b'6' => b'^',
-        b'7' => b'&',
-        b'8' => b'*',
-        b'9' => b'(',
-        b'0' => b')',
-        b'-' => b'_',
-        b'=' => b'+',
-        b'`' => b'~',
-        b',' => b'<',
-        b'.' => b'>',
-        b'/' => b'?',
-        b'[' => b'{',
-        b']' => b'}',
-        b'\\' => b'|',
-        b';' => b':',
-        _ => ch,
-    };
-    ch
+// set by SIGUSR1, screen is dumped in main loop
+static DUMP_SCREEN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigusr1(_: nix::libc::c_int) {
+    DUMP_SCREEN.store(true, Ordering::SeqCst);
+}
+
+fn install_dump_handler() {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sigusr1),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGUSR1, &action).unwrap() };
+}
+
+fn dump_screen(console: &Console, out: &mut impl Write) {
+    if let Err(e) = writeln!(out, "{}", console.snapshot()) {
+        eprintln!("Failed to dump screen: {}", e);
+    }
 }
 
 fn find_sdl_gl_driver() -> Option<u32> {
@@ -58,251 +63,1392 @@ fn find_sdl_gl_driver() -> Option<u32> {
     None
 }
 
+// write as much as pty accepts, keep the rest for next loop
+fn flush_pty(out: &mut impl Write, pending: &mut Vec<u8>) -> std::io::Result<()> {
+    while !pending.is_empty() {
+        match out.write(pending) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(len) => {
+                pending.drain(..len);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// OSC 8 link, other schemes may run local files
+fn open_link(uri: &str) {
+    let allowed = ["http://", "https://", "ftp://", "mailto:"];
+    if !allowed.iter().any(|scheme| uri.starts_with(scheme)) {
+        eprintln!("Refused to open link {:?}", uri);
+        return;
+    }
+    match std::process::Command::new("xdg-open").arg(uri).spawn() {
+        // reaped in background
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => eprintln!("Failed to run xdg-open: {}", e),
+    }
+}
+
+// window pixel to console cell
+fn cell_at(console: &Console, window_size: (u32, u32), x: i32, y: i32) -> (i32, i32) {
+    let canvas_size = console.get_canvas_size();
+    let rect = letterbox(canvas_size, window_size);
+    let font_size = console.get_font_size();
+    let size = console.get_size();
+    let x = (x - rect.x()) * canvas_size.0 as i32 / rect.width().max(1) as i32 / font_size.0;
+    let y = (y - rect.y()) * canvas_size.1 as i32 / rect.height().max(1) as i32 / font_size.1;
+    (x.max(0).min(size.0 - 1), y.max(0).min(size.1 - 1))
+}
+
 struct PTY {
     pub master: RawFd,
     pub slave: RawFd,
 }
 
-fn openpty() -> Result<PTY, String> {
-    // Open a new PTY master
-    let master_fd = posix_openpt(OFlag::O_RDWR).unwrap();
+impl PTY {
+    fn close_master(&mut self) {
+        if self.master >= 0 {
+            unistd::close(self.master).unwrap();
+            self.master = -1;
+        }
+    }
+
+    fn close_slave(&mut self) {
+        if self.slave >= 0 {
+            unistd::close(self.slave).unwrap();
+            self.slave = -1;
+        }
+    }
+}
+
+// writes go to master
+impl Write for PTY {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        unistd::write(self.master, buf).map_err(|e| match e {
+            nix::Error::Sys(errno) => errno.into(),
+            e => std::io::Error::new(std::io::ErrorKind::Other, e),
+        })
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PTY {
+    fn drop(&mut self) {
+        for fd in [self.master, self.slave].iter() {
+            if *fd >= 0 {
+                unistd::close(*fd).ok();
+            }
+        }
+    }
+}
+
+// pid of running child for panic hook, 0 if none
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
 
-    grantpt(&master_fd).unwrap();
-    unlockpt(&master_fd).unwrap();
+// time a hung up child gets to exit before it is killed
+const REAP_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+// hang up and reap child if it is still running,
+// killed if it ignores SIGHUP so that exit never blocks
+fn reap_child() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+    let pid = CHILD_PID.swap(0, Ordering::SeqCst);
+    if pid == 0 {
+        return;
+    }
+    let pid = unistd::Pid::from_raw(pid);
+    kill(pid, Signal::SIGHUP).ok();
+    let deadline = std::time::Instant::now() + REAP_TIMEOUT;
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) if std::time::Instant::now() < deadline => {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            Ok(WaitStatus::StillAlive) => {
+                kill(pid, Signal::SIGKILL).ok();
+                waitpid(pid, None).ok();
+                return;
+            }
+            _ => return,
+        }
+    }
+}
+
+// reap child on every exit path of start, including unwinding
+struct ChildGuard;
+
+impl ChildGuard {
+    fn new(pid: unistd::Pid) -> ChildGuard {
+        CHILD_PID.store(pid.as_raw(), Ordering::SeqCst);
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            reap_child();
+            default_hook(info);
+        }));
+        ChildGuard
+    }
+
+    // child is already reaped by waitpid
+    fn exited(&self) {
+        CHILD_PID.store(0, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        reap_child();
+    }
+}
+
+// initial line discipline of slave
+struct TermiosConfig {
+    // ctrl-c, ctrl-z and ctrl-\ generate signals
+    isig: bool,
+    echo: bool,
+}
+
+impl Default for TermiosConfig {
+    fn default() -> TermiosConfig {
+        TermiosConfig {
+            isig: true,
+            echo: true,
+        }
+    }
+}
+
+// cooked mode, erase char matches backspace key
+fn set_termios(fd: RawFd, config: &TermiosConfig) -> nix::Result<()> {
+    use nix::sys::termios::{
+        tcgetattr, tcsetattr, InputFlags, LocalFlags, OutputFlags, SetArg,
+        SpecialCharacterIndices as Cc,
+    };
+    let mut termios = tcgetattr(fd)?;
+    termios.input_flags |= InputFlags::ICRNL | InputFlags::IXON;
+    termios.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+    termios.local_flags |=
+        LocalFlags::ICANON | LocalFlags::ECHOE | LocalFlags::ECHOK | LocalFlags::IEXTEN;
+    for (index, ch) in [
+        (Cc::VERASE, 0x7f),
+        (Cc::VINTR, 0x03),
+        (Cc::VQUIT, 0x1c),
+        (Cc::VSUSP, 0x1a),
+        (Cc::VEOF, 0x04),
+        (Cc::VKILL, 0x15),
+    ]
+    .iter()
+    {
+        termios.control_chars[*index as usize] = *ch;
+    }
+    termios.local_flags.set(LocalFlags::ISIG, config.isig);
+    termios.local_flags.set(LocalFlags::ECHO, config.echo);
+    tcsetattr(fd, SetArg::TCSANOW, &termios)
+}
+
+fn openpty(config: &TermiosConfig) -> Result<PTY, String> {
+    // Open a new PTY master, closed on drop if later steps fail
+    let master_fd = posix_openpt(OFlag::O_RDWR).map_err(|e| format!("posix_openpt: {}", e))?;
+
+    grantpt(&master_fd).map_err(|e| format!("grantpt: {}", e))?;
+    unlockpt(&master_fd).map_err(|e| format!("unlockpt: {}", e))?;
 
     // Get the name of the slave
-    let slave_name = unsafe { ptsname(&master_fd).unwrap() };
+    let slave_name = unsafe { ptsname(&master_fd) }.map_err(|e| format!("ptsname: {}", e))?;
 
     // Try to open the slave
-    let slave_fd = open(Path::new(&slave_name), OFlag::O_RDWR, Mode::empty()).unwrap();
+    let slave_fd = open(Path::new(&slave_name), OFlag::O_RDWR, Mode::empty())
+        .map_err(|e| format!("open {}: {}", slave_name, e))?;
 
     use std::os::unix::io::IntoRawFd;
-    Ok(PTY {
+    let pty = PTY {
         master: master_fd.into_raw_fd(),
         slave: slave_fd,
+    };
+    set_termios(pty.slave, config).map_err(|e| format!("set termios: {}", e))?;
+    Ok(pty)
+}
+
+// size of terminal fsdterm is launched in, None if stdin is not a tty
+fn get_host_winsize() -> Option<(i32, i32)> {
+    if !unistd::isatty(0).unwrap_or(false) {
+        return None;
+    }
+    get_winsize(0)
+}
+
+// None if size is not set
+fn get_winsize(fd: RawFd) -> Option<(i32, i32)> {
+    const TIOCGWINSZ: usize = 0x5413;
+    nix::ioctl_read_bad!(tiocgwinsz, TIOCGWINSZ, nix::pty::Winsize);
+    let mut winsize = nix::pty::Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe { tiocgwinsz(fd, &mut winsize) }.ok()?;
+    if winsize.ws_col == 0 || winsize.ws_row == 0 {
+        return None;
+    }
+    Some((winsize.ws_col as i32, winsize.ws_row as i32))
+}
+
+fn set_winsize(fd: RawFd, size: (i32, i32)) {
+    const TIOCSWINSZ: usize = 0x5414;
+    nix::ioctl_write_ptr_bad!(tiocswinsz, TIOCSWINSZ, nix::pty::Winsize);
+    let winsize = nix::pty::Winsize {
+        ws_row: size.1 as u16,
+        ws_col: size.0 as u16,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe { tiocswinsz(fd, &winsize).unwrap() };
+}
+
+// shown in hold mode after child exited
+fn exit_message(status: nix::sys::wait::WaitStatus) -> String {
+    use nix::sys::wait::WaitStatus;
+    let code = match status {
+        WaitStatus::Exited(_, code) => code,
+        // like shells
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _ => -1,
+    };
+    format!("\r\n[Process exited with code {}]", code)
+}
+
+// argv and environment of child, built before fork since only
+// async-signal-safe calls are allowed in the child of a threaded process
+struct ChildCommand {
+    // only kept alive for argv and envp
+    _args: Vec<CString>,
+    _env: Vec<CString>,
+    // null terminated pointers into args and env
+    argv: Vec<*const nix::libc::c_char>,
+    envp: Vec<*const nix::libc::c_char>,
+    // written to stderr if exec fails
+    error: Vec<u8>,
+}
+
+impl ChildCommand {
+    // shell if command is empty, size is passed as COLUMNS and LINES
+    fn new(command: &[String], size: (i32, i32)) -> ChildCommand {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let args: Vec<CString> = if command.is_empty() {
+            vec![CString::new("/bin/bash").unwrap()]
+        } else {
+            command
+                .iter()
+                .map(|arg| CString::new(arg.as_str()).unwrap())
+                .collect()
+        };
+        let mut env: Vec<CString> = std::env::vars_os()
+            .filter(|(key, _)| !matches!(key.to_str(), Some("TERM" | "COLUMNS" | "LINES")))
+            .filter_map(|(key, value)| {
+                let mut pair = key.into_vec();
+                pair.push(b'=');
+                pair.extend_from_slice(value.as_bytes());
+                CString::new(pair).ok()
+            })
+            .collect();
+        env.push(CString::new("TERM=dumb").unwrap());
+        env.push(CString::new(format!("COLUMNS={}", size.0)).unwrap());
+        env.push(CString::new(format!("LINES={}", size.1)).unwrap());
+        let pointers = |strings: &[CString]| {
+            strings
+                .iter()
+                .map(|string| string.as_ptr())
+                .chain(std::iter::once(std::ptr::null()))
+                .collect()
+        };
+        ChildCommand {
+            argv: pointers(&args),
+            envp: pointers(&env),
+            error: format!("Failed to execute {:?}\n", args[0]).into_bytes(),
+            _args: args,
+            _env: env,
+        }
+    }
+
+    // child side of fork, nothing is allocated
+    fn exec(&self) -> ! {
+        unsafe {
+            nix::libc::execvpe(self.argv[0], self.argv.as_ptr(), self.envp.as_ptr());
+        }
+        self.fail()
+    }
+
+    fn fail(&self) -> ! {
+        unistd::write(2, &self.error).ok();
+        unsafe { nix::libc::_exit(127) }
+    }
+}
+
+// child side of fork, runs command on slave
+fn exec_child(pty: &mut PTY, command: &ChildCommand) -> ! {
+    pty.close_master();
+
+    // create process group
+    if unistd::setsid().is_err() {
+        command.fail();
+    }
+
+    const TIOCSCTTY: usize = 0x540E;
+    nix::ioctl_write_int_bad!(tiocsctty, TIOCSCTTY);
+    if unsafe { tiocsctty(pty.slave, 0) }.is_err() {
+        command.fail();
+    }
+
+    for fd in 0..3 {
+        // stdin, stdout and stderr
+        if unistd::dup2(pty.slave, fd).is_err() {
+            command.fail();
+        }
+    }
+    pty.close_slave();
+    command.exec()
+}
+
+// main loop state besides sdl
+struct Session {
+    // writes are buffered instead of blocking
+    pty_out: Vec<u8>,
+    // hold mode, pty is no longer used after read or write fails
+    pty_closed: bool,
+    // hold mode, child exited and message is shown
+    held: bool,
+    // minimized or hidden, pty is still read but not rendered
+    hidden: bool,
+    // latest size of resize events not applied yet
+    pending_size: Option<(i32, i32)>,
+}
+
+impl Session {
+    fn new() -> Session {
+        Session {
+            pty_out: Vec::new(),
+            pty_closed: false,
+            held: false,
+            hidden: false,
+            pending_size: None,
+        }
+    }
+
+    // whole screen is drawn when shown again
+    fn set_hidden(&mut self, console: &mut Console, hidden: bool) {
+        self.hidden = hidden;
+        if !hidden {
+            console.mark_all_dirty();
+        }
+    }
+
+    // fit drawable pixels, applied later by apply_resize
+    fn request_resize(&mut self, console: &Console, drawable_size: (u32, u32)) {
+        let font_size = console.get_font_size();
+        self.pending_size = Some((
+            (drawable_size.0 as i32 / font_size.0).max(1),
+            (drawable_size.1 as i32 / font_size.1).max(1),
+        ));
+    }
+
+    // resize events are coalesced into one resize per loop,
+    // true if size changed
+    fn apply_resize(&mut self, console: &mut Console, fd: RawFd) -> bool {
+        match self.pending_size.take() {
+            Some(size) if size != console.get_size() => {
+                console.resize(size);
+                set_winsize(fd, size);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // pty is no longer used in hold mode, Err if window should be closed
+    fn close_pty(&mut self, hold: bool) -> Result<(), ()> {
+        if !hold {
+            return Err(());
+        }
+        self.pty_closed = true;
+        Ok(())
+    }
+
+    // write pending bytes and process at most budget bytes of output,
+    // rest of a flood is read after rendering and input,
+    // return processed bytes, Err if window should be closed
+    fn pump_pty(
+        &mut self,
+        console: &mut Console,
+        pty: &mut PTY,
+        budget: usize,
+        hold: bool,
+    ) -> Result<usize, ()> {
+        let mut processed = 0;
+        while !self.pty_closed && processed < budget {
+            let mut readable = nix::sys::select::FdSet::new();
+            readable.insert(pty.master);
+            let mut writable = nix::sys::select::FdSet::new();
+            if !self.pty_out.is_empty() {
+                writable.insert(pty.master);
+            }
+
+            use nix::sys::time::TimeValLike;
+            let result = nix::sys::select::select(
+                None,
+                Some(&mut readable),                        // read
+                Some(&mut writable),                        // write
+                None,                                       // error
+                Some(&mut nix::sys::time::TimeVal::zero()), // polling
+            );
+            // interrupted by signal, poll again next loop
+            if let Err(nix::Error::Sys(nix::errno::Errno::EINTR)) = result {
+                break;
+            }
+            result.unwrap();
+
+            if writable.contains(pty.master) {
+                if let Err(e) = flush_pty(pty, &mut self.pty_out) {
+                    eprintln!("Failed to write to child: {}", e);
+                    self.close_pty(hold)?;
+                    break;
+                }
+            }
+            if !readable.contains(pty.master) {
+                break;
+            }
+            let mut buf = [0];
+            if let Err(e) = nix::unistd::read(pty.master, &mut buf) {
+                eprintln!("Nothing to read from child: {}", e);
+                self.close_pty(hold)?;
+                break;
+            }
+            processed += 1;
+            console.activity();
+            if let Some(report) = console.put_char(buf[0]) {
+                self.pty_out.extend(report);
+            }
+        }
+        Ok(processed)
+    }
+
+    // false if window should be closed, otherwise pty is no longer
+    // used and exit message is shown until a key is pressed
+    fn child_exited(
+        &mut self,
+        console: &mut Console,
+        status: nix::sys::wait::WaitStatus,
+        hold: bool,
+    ) -> bool {
+        if !hold {
+            return false;
+        }
+        self.held = true;
+        self.pty_closed = true;
+        self.pty_out.clear();
+        console.scroll_to_bottom();
+        for ch in exit_message(status).bytes() {
+            console.put_char(ch);
+        }
+        true
+    }
+
+    // previous frame is kept during synchronized update
+    fn should_draw(&self, console: &Console) -> bool {
+        !self.hidden && console.should_render(std::time::Instant::now())
+    }
+}
+
+// both sides of pty are closed if fork fails
+fn fork_or_close(
+    pty: &mut PTY,
+    fork: impl FnOnce() -> nix::Result<unistd::ForkResult>,
+) -> Result<unistd::ForkResult, String> {
+    fork().map_err(|e| {
+        pty.close_master();
+        pty.close_slave();
+        format!("Failed to fork: {}", e)
     })
 }
 
-fn start(pty: &PTY) {
-    // console is created before creating process
-    let mut console = Console::new((80, 24));
+// command is run instead of shell if not empty
+// console is created before creating process
+// window is kept after child exit until a key is pressed if hold
+// at most frame_bytes of pty output are processed between frames
+fn start(
+    mut pty: PTY,
+    command: &[String],
+    mut console: Console,
+    mut control: Option<ControlSocket>,
+    hold: bool,
+    frame_bytes: usize,
+) -> Result<(), String> {
+    set_winsize(pty.master, console.get_size());
 
-    match unistd::fork() {
-        Ok(unistd::ForkResult::Parent { child: _, .. }) => {
-            unistd::close(pty.slave).unwrap();
+    let child_command = ChildCommand::new(command, console.get_size());
+    match fork_or_close(&mut pty, unistd::fork)? {
+        unistd::ForkResult::Parent { child, .. } => {
+            let child_guard = ChildGuard::new(child);
+            pty.close_slave();
+            // writes are buffered in session instead of blocking
+            fcntl(pty.master, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+            let mut session = Session::new();
+            install_dump_handler();
 
             let sdl_context = sdl2::init().unwrap();
             let video_subsystem = sdl_context.video().unwrap();
-            let window_size: (u32, u32) = (1200, 480);
+            let window_size = console.get_canvas_size();
 
-            let mut shift: bool = false;
-            let mut ctrl: bool = false;
+            let mut keyboard = Keyboard::default();
 
-            let window = video_subsystem
-                .window("fsdterm", window_size.0 as u32, window_size.1 as u32)
-                .opengl()
+            // opengl if available, otherwise sdl picks first usable driver
+            let gl_driver = find_sdl_gl_driver();
+            let mut window_builder =
+                video_subsystem.window("fsdterm", window_size.0 as u32, window_size.1 as u32);
+            if gl_driver.is_some() {
+                window_builder.opengl();
+            }
+            let window = window_builder
+                .resizable()
+                .allow_highdpi()
                 .position_centered()
                 .build()
-                .unwrap();
+                .map_err(|e| format!("Failed to create window: {}", e))?;
 
-            let mut canvas = window
-                .into_canvas()
-                .index(find_sdl_gl_driver().unwrap())
-                .build()
-                .unwrap();
+            let mut canvas = match gl_driver {
+                Some(index) => window.into_canvas().index(index),
+                None => window.into_canvas(),
+            }
+            .build()
+            .map_err(|e| format!("Failed to create renderer: {}", e))?;
+            println!("Render driver {}", canvas.info().name);
+
+            // on hidpi window size is in points, shrink it so that
+            // canvas maps to drawable pixels one to one
+            let drawable_size = canvas.output_size()?;
+            if drawable_size.0 > window_size.0 {
+                let scale = drawable_size.0 as f32 / window_size.0 as f32;
+                canvas
+                    .window_mut()
+                    .set_size(
+                        (window_size.0 as f32 / scale) as u32,
+                        (window_size.1 as f32 / scale) as u32,
+                    )
+                    .map_err(|e| e.to_string())?;
+            }
 
             let texture_creator = canvas.texture_creator();
-            let mut texture = texture_creator
-                .create_texture_static(
-                    Some(sdl2::pixels::PixelFormatEnum::RGB24),
-                    window_size.0,
-                    window_size.1,
-                )
-                .unwrap();
+            let mut renderer = SdlRenderer::new(
+                canvas,
+                &texture_creator,
+                video_subsystem.clipboard(),
+                window_size,
+            );
 
             let mut event_pump = sdl_context.event_pump().unwrap();
 
+            // debug overlay statistics
+            let mut frame_start = std::time::Instant::now();
+            let mut pty_bytes = 0;
+            let mut bytes_start = std::time::Instant::now();
+            let mut bytes_per_sec = 0;
+
             'main_loop: loop {
                 // println!("wait...");
                 std::thread::sleep(std::time::Duration::new(0, 10_000_000u32));
-                'readable_pts: loop {
-                    let mut readable = nix::sys::select::FdSet::new();
-                    readable.insert(pty.master);
-
-                    use nix::sys::time::TimeValLike;
-                    nix::sys::select::select(
-                        None,
-                        Some(&mut readable),                        // read
-                        None,                                       // write
-                        None,                                       // error
-                        Some(&mut nix::sys::time::TimeVal::zero()), // polling
-                    )
-                    .unwrap();
-
-                    if readable.contains(pty.master) {
-                        let mut buf = [0];
-                        if let Err(e) = nix::unistd::read(pty.master, &mut buf) {
-                            eprintln!("Nothing to read from child: {}", e);
+                match session.pump_pty(&mut console, &mut pty, frame_bytes, hold) {
+                    Ok(processed) => pty_bytes += processed,
+                    Err(()) => break 'main_loop,
+                }
+                // child exited, other processes may still hold the slave
+                use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+                match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+                    _ if session.held => {}
+                    Ok(WaitStatus::StillAlive) => {}
+                    Ok(status @ WaitStatus::Exited(..)) | Ok(status @ WaitStatus::Signaled(..)) => {
+                        child_guard.exited();
+                        if !session.child_exited(&mut console, status, hold) {
                             break 'main_loop;
                         }
-                        if let Some(report) = console.put_char(buf[0]) {
-                            for c in report.iter() {
-                                nix::unistd::write(pty.master, &[set_shift(*c, shift); 1]).unwrap();
-                            }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Failed to wait child: {}", e);
+                        break 'main_loop;
+                    }
+                }
+                if let Some(control) = control.as_mut() {
+                    control.poll(&console, &mut session.pty_out);
+                }
+                if let Some(title) = console.take_title() {
+                    if let Err(e) = renderer.set_title(&title) {
+                        eprintln!("Invalid title: {}", e);
+                    }
+                }
+                if DUMP_SCREEN.swap(false, Ordering::SeqCst) {
+                    dump_screen(&console, &mut std::io::stderr());
+                }
+                session.apply_resize(&mut console, pty.master);
+                if session.should_draw(&console) {
+                    if console.show_overlay {
+                        if bytes_start.elapsed().as_secs() >= 1 {
+                            bytes_per_sec = pty_bytes;
+                            pty_bytes = 0;
+                            bytes_start = std::time::Instant::now();
                         }
-                    } else {
-                        break 'readable_pts;
+                        let dirty = console.take_damage().len();
+                        console.set_overlay_text(format!(
+                            "{}ms {}B/s {}dirty",
+                            frame_start.elapsed().as_millis(),
+                            bytes_per_sec,
+                            dirty,
+                        ));
                     }
+                    frame_start = std::time::Instant::now();
+                    console.draw(&mut renderer);
                 }
-                console.render();
 
-                texture
-                    .update(None, &console.canvas.data, window_size.0 as usize * 3)
-                    .unwrap();
-
-                canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
-                canvas.clear();
-                canvas.copy(&texture, None, None).unwrap();
-                canvas.present();
+                if !session.hidden {
+                    renderer.present().unwrap();
+                }
 
                 // read input
                 for event in event_pump.poll_iter() {
                     match event {
                         Event::Quit { .. } => break 'main_loop,
-                        Event::KeyDown { keycode: code, .. } => {
-                            let mut ch = match code {
-                                Some(Keycode::A) => Some(vec![b'a']),
-                                Some(Keycode::B) => Some(vec![b'b']),
-                                Some(Keycode::C) => Some(vec![b'c']),
-                                Some(Keycode::D) => Some(vec![b'd']),
-                                Some(Keycode::E) => Some(vec![b'e']),
-                                Some(Keycode::F) => Some(vec![b'f']),
-                                Some(Keycode::G) => Some(vec![b'g']),
-                                Some(Keycode::H) => Some(vec![b'h']),
-                                Some(Keycode::I) => Some(vec![b'i']),
-                                Some(Keycode::J) => Some(vec![b'j']),
-                                Some(Keycode::K) => Some(vec![b'k']),
-                                Some(Keycode::L) => Some(vec![b'l']),
-                                Some(Keycode::M) => Some(vec![b'm']),
-                                Some(Keycode::N) => Some(vec![b'n']),
-                                Some(Keycode::O) => Some(vec![b'o']),
-                                Some(Keycode::P) => Some(vec![b'p']),
-                                Some(Keycode::Q) => Some(vec![b'q']),
-                                Some(Keycode::R) => Some(vec![b'r']),
-                                Some(Keycode::S) => Some(vec![b's']),
-                                Some(Keycode::T) => Some(vec![b't']),
-                                Some(Keycode::U) => Some(vec![b'u']),
-                                Some(Keycode::V) => Some(vec![b'v']),
-                                Some(Keycode::W) => Some(vec![b'w']),
-                                Some(Keycode::X) => Some(vec![b'x']),
-                                Some(Keycode::Y) => Some(vec![b'y']),
-                                Some(Keycode::Z) => Some(vec![b'z']),
-                                Some(Keycode::Quote) => Some(vec![b'\'']),
-                                Some(Keycode::Comma) => Some(vec![b',']),
-                                Some(Keycode::Minus) => Some(vec![b'-']),
-                                Some(Keycode::Period) => Some(vec![b'.']),
-                                Some(Keycode::Slash) => Some(vec![b'/']),
-                                Some(Keycode::Num0) => Some(vec![b'0']),
-                                Some(Keycode::Num1) => Some(vec![b'1']),
-                                Some(Keycode::Num2) => Some(vec![b'2']),
-                                Some(Keycode::Num3) => Some(vec![b'3']),
-                                Some(Keycode::Num4) => Some(vec![b'4']),
-                                Some(Keycode::Num5) => Some(vec![b'5']),
-                                Some(Keycode::Num6) => Some(vec![b'6']),
-                                Some(Keycode::Num7) => Some(vec![b'7']),
-                                Some(Keycode::Num8) => Some(vec![b'8']),
-                                Some(Keycode::Num9) => Some(vec![b'9']),
-                                Some(Keycode::Semicolon) => Some(vec![b';']),
-                                Some(Keycode::Equals) => Some(vec![b'=']),
-                                Some(Keycode::LeftBracket) => Some(vec![b'[']),
-                                Some(Keycode::RightBracket) => Some(vec![b']']),
-                                Some(Keycode::Backslash) => Some(vec![b'\\']),
-                                Some(Keycode::Backquote) => Some(vec![b'`']),
-                                Some(Keycode::Backspace) => Some(vec![8, b' ', 8]),
-                                Some(Keycode::Escape) => Some(vec![27]),
-                                Some(Keycode::Space) => Some(vec![b' ']),
-                                Some(Keycode::LShift) | Some(Keycode::RShift) => {
-                                    shift = true;
-                                    None
-                                }
-                                Some(Keycode::LCtrl) | Some(Keycode::RCtrl) => {
-                                    ctrl = true;
-                                    None
-                                }
-                                Some(Keycode::Return) => Some(vec![b'\n']),
-                                Some(Keycode::Left) => Some(vec![27, b'O', b'D']),
-                                Some(Keycode::Right) => Some(vec![27, b'O', b'C']),
-                                Some(Keycode::Down) => Some(vec![27, b'O', b'B']),
-                                Some(Keycode::Up) => Some(vec![27, b'O', b'A']),
-                                _ => None,
-                            };
-
-                            ch = match ch {
-                                None => None,
-                                Some(mut ch) => {
-                                    Some(ch.iter_mut().map(|x| set_shift(*x, shift)).collect())
-                                }
-                            };
-
-                            if ctrl {
-                                if let Some(c) = ch.clone() {
-                                    ch = match c[0] {
-                                        b'a'..=b'z' => Some(vec![c[0] - b'a' + 1]),
-                                        b'[' => Some(vec![27]),
-                                        b'\\' => Some(vec![28]),
-                                        b']' => Some(vec![29]),
-                                        b'^' => Some(vec![30]),
-                                        b'_' => Some(vec![31]),
-                                        _ => Some(vec![c[0]]),
-                                    }
-                                }
+                        Event::KeyDown { .. } if session.held => break 'main_loop,
+                        Event::Window {
+                            win_event: WindowEvent::Resized(..),
+                            ..
+                        } => {
+                            // event size is in points on hidpi
+                            session.request_resize(&console, renderer.drawable_size());
+                        }
+                        Event::Window {
+                            win_event: WindowEvent::Minimized,
+                            ..
+                        }
+                        | Event::Window {
+                            win_event: WindowEvent::Hidden,
+                            ..
+                        } => session.set_hidden(&mut console, true),
+                        Event::Window {
+                            win_event: WindowEvent::Restored,
+                            ..
+                        }
+                        | Event::Window {
+                            win_event: WindowEvent::Shown,
+                            ..
+                        } => session.set_hidden(&mut console, false),
+                        Event::Window {
+                            win_event: WindowEvent::FocusGained,
+                            ..
+                        } => {
+                            if let Some(report) = console.focus(true) {
+                                session.pty_out.extend(report);
+                            }
+                        }
+                        Event::Window {
+                            win_event: WindowEvent::FocusLost,
+                            ..
+                        } => {
+                            if let Some(report) = console.focus(false) {
+                                session.pty_out.extend(report);
                             }
-                            if let Some(ch) = ch {
-                                nix::unistd::write(pty.master, &ch).unwrap();
+                        }
+                        Event::MouseButtonDown {
+                            mouse_btn: MouseButton::Left,
+                            x,
+                            y,
+                            ..
+                        } if keyboard.ctrl => {
+                            if let Some(uri) =
+                                console.link_at(cell_at(&console, renderer.size(), x, y))
+                            {
+                                open_link(uri);
                             }
                         }
-                        Event::KeyUp { keycode: code, .. } => {
-                            match code {
-                                Some(Keycode::LShift) | Some(Keycode::RShift) => shift = false,
-                                Some(Keycode::LCtrl) | Some(Keycode::RCtrl) => ctrl = false,
-                                _ => {}
-                            };
+                        Event::MouseButtonDown {
+                            mouse_btn: MouseButton::Left,
+                            x,
+                            y,
+                            ..
+                        } => console.start_selection(cell_at(&console, renderer.size(), x, y)),
+                        Event::MouseMotion {
+                            mousestate, x, y, ..
+                        } => {
+                            let pos = cell_at(&console, renderer.size(), x, y);
+                            console.set_pointer(Some(pos));
+                            if mousestate.left() {
+                                console.extend_selection(pos);
+                            }
+                        }
+                        Event::Window {
+                            win_event: WindowEvent::Leave,
+                            ..
+                        } => console.set_pointer(None),
+                        Event::MouseWheel { y, .. } => {
+                            if let Some(bytes) = console.wheel(y * 3) {
+                                session.pty_out.extend(bytes);
+                            }
                         }
+                        // copy on release like xterm
+                        Event::MouseButtonUp {
+                            mouse_btn: MouseButton::Left,
+                            ..
+                        } => {
+                            if let Some(text) = console.finish_selection() {
+                                renderer.set_clipboard(&text);
+                            }
+                        }
+                        Event::KeyDown {
+                            keycode, repeat, ..
+                        } => match keyboard.key_down(&mut console, keycode, repeat) {
+                            Some(KeyAction::Input(bytes)) => session.pty_out.extend(bytes),
+                            Some(KeyAction::Copy(text)) => renderer.set_clipboard(&text),
+                            Some(KeyAction::Paste) => {
+                                if let Some(text) = renderer.clipboard() {
+                                    if let Some(bytes) = console.paste(&text) {
+                                        session.pty_out.extend(bytes);
+                                    }
+                                }
+                            }
+                            Some(KeyAction::Break) => {
+                                if let Err(e) = nix::sys::termios::tcsendbreak(pty.master, 0) {
+                                    eprintln!("Failed to send break: {}", e);
+                                }
+                            }
+                            None => {}
+                        },
+                        Event::KeyUp { keycode, .. } => keyboard.key_up(keycode),
                         _ => {}
                     }
                 }
+                // bytes of this event batch go out in one write,
+                // instead of waiting for select in next loop
+                if !session.pty_closed && !session.pty_out.is_empty() {
+                    if let Err(e) = flush_pty(&mut pty, &mut session.pty_out) {
+                        eprintln!("Failed to write to child: {}", e);
+                        if session.close_pty(hold).is_err() {
+                            break 'main_loop;
+                        }
+                    }
+                }
             }
-
-            // nix::sys::wait::waitpid(child, None);
         }
-        Ok(unistd::ForkResult::Child) => {
-            unistd::close(pty.master).unwrap();
+        unistd::ForkResult::Child => exec_child(&mut pty, &child_command),
+    }
+    Ok(())
+}
 
-            // create process group
-            unistd::setsid().unwrap();
+// feed recorded pty output to console, then save rendered screen
+fn replay(replay_path: &str, screenshot_path: &str, mut console: Console) -> Result<(), String> {
+    let data = std::fs::read(replay_path).map_err(|e| e.to_string())?;
+    for ch in data.into_iter() {
+        console.put_char(ch);
+    }
+    let mut renderer = PngRenderer::new(screenshot_path);
+    console.draw(&mut renderer);
+    renderer.present()
+}
 
-            const TIOCSCTTY: usize = 0x540E;
-            nix::ioctl_write_int_bad!(tiocsctty, TIOCSCTTY);
-            unsafe { tiocsctty(pty.slave, 0).unwrap() };
+// like 80x24
+fn parse_geometry(arg: &str) -> Option<(i32, i32)> {
+    let mut iter = arg
+        .split('x')
+        .map(|x| x.parse::<i32>().ok().filter(|x| *x > 0));
+    let geometry = (iter.next()??, iter.next()??);
+    if iter.next().is_some() {
+        return None;
+    }
+    Some(geometry)
+}
 
-            unistd::dup2(pty.slave, 0).unwrap(); // stdin
-            unistd::dup2(pty.slave, 1).unwrap(); // stdout
-            unistd::dup2(pty.slave, 2).unwrap(); // stderr
-            unistd::close(pty.slave).unwrap();
+fn parse_padding(arg: &str) -> Option<(i32, i32)> {
+    let mut iter = arg
+        .split(',')
+        .map(|x| x.parse::<i32>().ok().filter(|x| *x >= 0));
+    let padding = (iter.next()??, iter.next()??);
+    if iter.next().is_some() {
+        return None;
+    }
+    Some(padding)
+}
+
+fn main() {
+    let mut replay_path = None;
+    let mut screenshot_path = None;
+    let mut command = Vec::new();
+    let mut console = Console::new((80, 24));
+    let mut control_path = None;
+    let mut geometry = None;
+    let mut hold = false;
+    let mut frame_bytes = 1 << 16;
+    let mut termios = TermiosConfig::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--replay" => replay_path = args.next(),
+            "--screenshot" => screenshot_path = args.next(),
+            // opt-in, see control.rs
+            "--control-socket" => control_path = args.next(),
+            // horizontal and vertical cell padding in pixels
+            "--padding" => match args.next().as_deref().and_then(parse_padding) {
+                Some(padding) => console.set_padding(padding),
+                None => {
+                    eprintln!("--padding requires X,Y");
+                    std::process::exit(1);
+                }
+            },
+            // columns and rows, like 80x24
+            "--geometry" => match args.next().as_deref().and_then(parse_geometry) {
+                Some(size) => geometry = Some(size),
+                None => {
+                    eprintln!("--geometry requires COLSxROWS");
+                    std::process::exit(1);
+                }
+            },
+            "--bell" => {
+                console.bell_mode = match args.next().as_deref() {
+                    Some("flash") => BellMode::Flash,
+                    Some("mark") => BellMode::Mark,
+                    Some("audible") => BellMode::Audible,
+                    Some("silent") => BellMode::Silent,
+                    _ => {
+                        eprintln!("--bell requires flash, mark, audible or silent");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--enter" => {
+                console.enter_key = match args.next().as_deref() {
+                    Some("cr") => EnterKey::Cr,
+                    Some("lf") => EnterKey::Lf,
+                    Some("crlf") => EnterKey::CrLf,
+                    _ => {
+                        eprintln!("--enter requires cr, lf or crlf");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--hold" => hold = true,
+            "--frame-bytes" => match args.next().and_then(|x| x.parse::<usize>().ok()) {
+                Some(n) if n > 0 => frame_bytes = n,
+                _ => {
+                    eprintln!("--frame-bytes requires a positive number");
+                    std::process::exit(1);
+                }
+            },
+            "--no-bce" => console.set_bce(false),
+            "--tab-width" => match args.next().and_then(|x| x.parse::<i32>().ok()) {
+                Some(width) if width > 0 => console.set_tab_width(width),
+                _ => {
+                    eprintln!("--tab-width requires a positive number");
+                    std::process::exit(1);
+                }
+            },
+            "--dim-unfocused" => console.unfocused_brightness = 0.6,
+            // render sixel images instead of ignoring them
+            "--sixel" => console.sixel = true,
+            // multiline paste is written without confirmation
+            "--no-paste-guard" => console.paste_guard = false,
+            // 8-bit CSI and DCS, breaks utf-8 input
+            "--c1-controls" => console.parser.c1_controls = true,
+            // ctrl-c and friends are sent as bytes, not signals
+            "--no-isig" => termios.isig = false,
+            "--no-echo" => termios.echo = false,
+            // bold keeps colors 0-7 instead of using bright ones
+            "--no-bold-bright" => console.bold_is_bright = false,
+            // cursor does not blink unless DECSCUSR asks for it
+            "--steady-cursor" => console.cursor_blink = false,
+            // CSI 2 J keeps cleared screen in scrollback
+            "--clear-to-scrollback" => console.clear_to_scrollback = true,
+            // seconds without activity until cursor stops blinking, 0 for never
+            "--blink-idle" => match args.next().and_then(|x| x.parse::<u64>().ok()) {
+                Some(secs) => console.blink_idle_timeout = std::time::Duration::from_secs(secs),
+                None => {
+                    eprintln!("--blink-idle requires seconds");
+                    std::process::exit(1);
+                }
+            },
+            // alternate screen keeps its own scrollback
+            "--alt-scrollback" => console.alt_screen_scrollback = true,
+            // ?1049 leaves saved cursor of DECSC alone, like ?47
+            "--no-alt-save-cursor" => console.alt_screen_save_cursor = false,
+            // alpha of the default is kept
+            "--selection-color" => match args.next().as_deref().and_then(parse_color_spec) {
+                Some(color) => console.selection_color[..3].copy_from_slice(&color[..3]),
+                None => {
+                    eprintln!("--selection-color requires #rrggbb or rgb:r/g/b");
+                    std::process::exit(1);
+                }
+            },
+            // the rest are command and its args
+            "-e" => {
+                command = args.by_ref().collect();
+                if command.is_empty() {
+                    eprintln!("-e requires a command");
+                    std::process::exit(1);
+                }
+            }
+            _ => {
+                eprintln!("Unknown argument: {}", arg);
+                std::process::exit(1);
+            }
+        }
+    }
 
-            use std::ffi::CString;
-            let path = CString::new("/bin/bash").unwrap();
-            std::env::set_var("TERM", "dumb");
-            std::env::set_var("COLUMNS", &console.get_size().0.to_string());
-            std::env::set_var("LINES", &console.get_size().1.to_string());
+    if let Some(size) = geometry {
+        console.resize(size);
+    }
 
-            unistd::execv(&path, &[]).unwrap();
+    match (replay_path, screenshot_path) {
+        (Some(replay_path), Some(screenshot_path)) => {
+            if let Err(e) = replay(&replay_path, &screenshot_path, console) {
+                eprintln!("Replay failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        (None, None) => {
+            // default to size of launching terminal, then 80x24
+            if geometry.is_none() {
+                if let Some(size) = get_host_winsize() {
+                    console.resize(size);
+                }
+            }
+            let control = control_path.map(|path| match ControlSocket::bind(&path) {
+                Ok(control) => control,
+                Err(e) => {
+                    eprintln!("Failed to bind control socket {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            });
+            let result = openpty(&termios)
+                .map_err(|e| format!("Failed to open pty: {}", e))
+                .and_then(|pty| start(pty, &command, console, control, hold, frame_bytes));
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            eprintln!("--replay and --screenshot must be used together");
+            std::process::exit(1);
         }
-        Err(_) => {}
     }
 }
 
-fn main() {
-    let pty = openpty().unwrap();
-    start(&pty);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // held by every test that forks, and by tests checking for closed fds
+    // since forked children inherit open fds
+    static FORK_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn termios_flags() {
+        use nix::sys::termios::{tcgetattr, LocalFlags};
+        let pty = openpty(&TermiosConfig::default()).unwrap();
+        let flags = tcgetattr(pty.slave).unwrap().local_flags;
+        assert!(flags.contains(LocalFlags::ISIG | LocalFlags::ECHO | LocalFlags::ICANON));
+        let config = TermiosConfig {
+            isig: false,
+            echo: false,
+        };
+        let pty = openpty(&config).unwrap();
+        let flags = tcgetattr(pty.slave).unwrap().local_flags;
+        assert!(!flags.intersects(LocalFlags::ISIG | LocalFlags::ECHO));
+        assert!(flags.contains(LocalFlags::ICANON));
+    }
+
+    #[test]
+    fn hidden_window_is_not_drawn() {
+        let mut console = Console::new((10, 2));
+        let mut session = Session::new();
+        assert!(session.should_draw(&console));
+        session.set_hidden(&mut console, true);
+        console.take_damage();
+        assert!(!session.should_draw(&console));
+        session.set_hidden(&mut console, false);
+        assert!(session.should_draw(&console));
+        assert!(console.take_damage().len() >= 20);
+    }
+
+    #[test]
+    fn resize_events_are_coalesced() {
+        let pty = openpty(&TermiosConfig::default()).unwrap();
+        let mut console = Console::new((10, 2));
+        let mut session = Session::new();
+        let font_size = console.get_font_size();
+        for cols in [20, 30, 40].iter() {
+            let size = ((cols * font_size.0) as u32, (5 * font_size.1) as u32);
+            session.request_resize(&console, size);
+        }
+        assert!(session.apply_resize(&mut console, pty.master));
+        assert_eq!(console.get_size(), (40, 5));
+        assert!(!session.apply_resize(&mut console, pty.master));
+    }
+
+    #[test]
+    fn replay_saves_rendered_png() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let replay_path = dir.join(format!("fsdterm-replay-{}", id));
+        let png_path = dir.join(format!("fsdterm-replay-{}.png", id));
+        std::fs::write(&replay_path, b"\x1b[41m \x1b[0m#").unwrap();
+        let console = Console::new((4, 1));
+        let font_size = console.get_font_size();
+        let result = replay(
+            replay_path.to_str().unwrap(),
+            png_path.to_str().unwrap(),
+            console,
+        );
+        let png = image::open(&png_path).map(|image| image.to_rgb8());
+        std::fs::remove_file(&replay_path).ok();
+        std::fs::remove_file(&png_path).ok();
+        result.unwrap();
+        let png = png.unwrap();
+        assert_eq!(
+            png.dimensions(),
+            (4 * font_size.0 as u32, font_size.1 as u32)
+        );
+        // red background of first cell, glyph in second
+        assert_eq!(png.get_pixel(0, 0).0, [205, 0, 0]);
+        let glyph = (font_size.0..font_size.0 * 2)
+            .flat_map(|x| (0..font_size.1).map(move |y| (x as u32, y as u32)))
+            .any(|(x, y)| png.get_pixel(x, y).0 != [0, 0, 0]);
+        assert!(glyph);
+    }
+
+    // accepts chunk bytes per write, then blocks after limit writes
+    struct SlowWriter {
+        written: Vec<u8>,
+        writes: usize,
+        limit: usize,
+        chunk: usize,
+    }
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.writes == self.limit {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.writes += 1;
+            let len = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_pty_retries_short_writes() {
+        let mut out = SlowWriter {
+            written: Vec::new(),
+            writes: 0,
+            limit: 2,
+            chunk: 2,
+        };
+        let mut pending = b"hello".to_vec();
+        flush_pty(&mut out, &mut pending).unwrap();
+        assert_eq!(out.written, b"hell");
+        assert_eq!(pending, b"o");
+        out.limit = 10;
+        flush_pty(&mut out, &mut pending).unwrap();
+        assert_eq!(out.written, b"hello");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn slave_termios_defaults() {
+        use nix::sys::termios::{
+            tcgetattr, InputFlags, OutputFlags, SpecialCharacterIndices as Cc,
+        };
+        let pty = openpty(&TermiosConfig::default()).unwrap();
+        let termios = tcgetattr(pty.slave).unwrap();
+        assert!(termios
+            .input_flags
+            .contains(InputFlags::ICRNL | InputFlags::IXON));
+        assert!(termios
+            .output_flags
+            .contains(OutputFlags::OPOST | OutputFlags::ONLCR));
+        assert_eq!(termios.control_chars[Cc::VERASE as usize], 0x7f);
+        assert_eq!(termios.control_chars[Cc::VINTR as usize], 0x03);
+        assert_eq!(termios.control_chars[Cc::VEOF as usize], 0x04);
+    }
+
+    #[test]
+    fn sigusr1_dumps_screen() {
+        install_dump_handler();
+        nix::sys::signal::raise(nix::sys::signal::Signal::SIGUSR1).unwrap();
+        assert!(DUMP_SCREEN.swap(false, Ordering::SeqCst));
+        let mut console = Console::new((10, 2));
+        for &ch in b"abc\r\ndef" {
+            console.put_char(ch);
+        }
+        let mut out = Vec::new();
+        dump_screen(&console, &mut out);
+        assert_eq!(out, b"abc\ndef\n");
+    }
+
+    #[test]
+    fn command_runs_and_exits() {
+        use nix::sys::wait::{waitpid, WaitStatus};
+        let mut pty = openpty(&TermiosConfig::default()).unwrap();
+        let command = ["sh", "-c", "echo $COLUMNS; exit 3"].map(String::from);
+        let command = ChildCommand::new(&command, (42, 5));
+        let _lock = FORK_LOCK.lock().unwrap();
+        match unistd::fork().unwrap() {
+            unistd::ForkResult::Child => exec_child(&mut pty, &command),
+            unistd::ForkResult::Parent { child } => {
+                pty.close_slave();
+                let mut output = Vec::new();
+                let mut buf = [0; 256];
+                // EIO once child has exited and slave is closed
+                while let Ok(len) = unistd::read(pty.master, &mut buf) {
+                    if len == 0 {
+                        break;
+                    }
+                    output.extend_from_slice(&buf[..len]);
+                }
+                assert_eq!(output, b"42\r\n");
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 3));
+            }
+        }
+    }
+
+    #[test]
+    fn pty_drop_closes_fds() {
+        use nix::errno::Errno;
+        let _lock = FORK_LOCK.lock().unwrap();
+        let mut buf = [0; 16];
+        // other side sees hangup once both fds are closed
+        let pty = openpty(&TermiosConfig::default()).unwrap();
+        let slave = unistd::dup(pty.slave).unwrap();
+        drop(pty);
+        assert_eq!(unistd::read(slave, &mut buf), Ok(0));
+        unistd::close(slave).unwrap();
+        let pty = openpty(&TermiosConfig::default()).unwrap();
+        let master = unistd::dup(pty.master).unwrap();
+        drop(pty);
+        assert_eq!(
+            unistd::read(master, &mut buf),
+            Err(nix::Error::Sys(Errno::EIO))
+        );
+        unistd::close(master).unwrap();
+    }
+
+    #[test]
+    fn winsize_of_pty() {
+        let pty = openpty(&TermiosConfig::default()).unwrap();
+        assert_eq!(get_winsize(pty.slave), None);
+        set_winsize(pty.master, (100, 30));
+        assert_eq!(get_winsize(pty.slave), Some((100, 30)));
+        assert_eq!(get_winsize(pty.master), Some((100, 30)));
+        let (read, write) = unistd::pipe().unwrap();
+        assert_eq!(get_winsize(read), None);
+        unistd::close(read).unwrap();
+        unistd::close(write).unwrap();
+    }
+
+    #[test]
+    fn hold_keeps_window_after_exit() {
+        use nix::sys::signal::Signal;
+        use nix::sys::wait::WaitStatus;
+        let pid = unistd::Pid::from_raw(1);
+        let mut console = Console::new((40, 3));
+        let mut session = Session::new();
+        session.pty_out.extend(b"ls\r");
+        assert!(!session.child_exited(&mut console, WaitStatus::Exited(pid, 3), false));
+        assert!(!session.held);
+        assert_eq!(console.snapshot(), "\n\n");
+        assert!(session.child_exited(&mut console, WaitStatus::Exited(pid, 3), true));
+        assert!(session.held && session.pty_closed);
+        assert!(session.pty_out.is_empty());
+        assert_eq!(console.snapshot(), "\n[Process exited with code 3]\n");
+        let status = WaitStatus::Signaled(pid, Signal::SIGKILL, false);
+        assert!(session.child_exited(&mut console, status, true));
+        assert!(console
+            .snapshot()
+            .ends_with("[Process exited with code 137]"));
+    }
+
+    #[test]
+    fn fork_failure_closes_pty() {
+        use nix::errno::Errno;
+        let mut pty = openpty(&TermiosConfig::default()).unwrap();
+        let result = fork_or_close(&mut pty, || Err(nix::Error::Sys(Errno::EAGAIN)));
+        assert!(result.unwrap_err().starts_with("Failed to fork"));
+        assert_eq!((pty.master, pty.slave), (-1, -1));
+    }
+
+    #[test]
+    fn canvas_fills_hidpi_drawable() {
+        let pty = openpty(&TermiosConfig::default()).unwrap();
+        let mut console = Console::new((10, 2));
+        let mut session = Session::new();
+        let font_size = console.get_font_size();
+        // window of 20x6 cells in points at scale 2
+        let points = ((20 * font_size.0) as u32 / 2, (6 * font_size.1) as u32 / 2);
+        let drawable = (points.0 * 2, points.1 * 2);
+        session.request_resize(&console, drawable);
+        session.apply_resize(&mut console, pty.master);
+        assert_eq!(console.get_canvas_size(), drawable);
+        // mouse events are in points
+        let cell = cell_at(&console, points, points.0 as i32 - 1, points.1 as i32 - 1);
+        assert_eq!(cell, (19, 5));
+    }
+
+    #[test]
+    fn key_batch_is_one_write() {
+        use sdl2::keyboard::Keycode;
+        let mut console = Console::new((10, 2));
+        let mut keyboard = Keyboard::default();
+        let mut session = Session::new();
+        for &code in [Keycode::L, Keycode::S, Keycode::Return].iter() {
+            if let Some(KeyAction::Input(bytes)) =
+                keyboard.key_down(&mut console, Some(code), false)
+            {
+                session.pty_out.extend(bytes);
+            }
+        }
+        let mut out = SlowWriter {
+            written: Vec::new(),
+            writes: 0,
+            limit: 10,
+            chunk: 4096,
+        };
+        flush_pty(&mut out, &mut session.pty_out).unwrap();
+        assert_eq!(out.written, b"ls\r");
+        assert_eq!(out.writes, 1);
+        assert!(session.pty_out.is_empty());
+    }
+
+    #[test]
+    fn pty_output_is_read_within_budget() {
+        let _lock = FORK_LOCK.lock().unwrap();
+        let mut pty = openpty(&TermiosConfig::default()).unwrap();
+        let mut console = Console::new((10, 11));
+        let mut session = Session::new();
+        unistd::write(pty.slave, &[b'a'; 100]).unwrap();
+        for &expected in [30, 30, 30, 10, 0].iter() {
+            assert_eq!(
+                session.pump_pty(&mut console, &mut pty, 30, false),
+                Ok(expected)
+            );
+        }
+        assert_eq!(console.snapshot().matches('a').count(), 100);
+        // slave closed, read fails
+        pty.close_slave();
+        assert_eq!(session.pump_pty(&mut console, &mut pty, 30, false), Err(()));
+        assert_eq!(session.pump_pty(&mut console, &mut pty, 30, true), Ok(0));
+        assert!(session.pty_closed);
+    }
+
+    #[test]
+    fn typed_text_is_echoed() {
+        let mut pty = openpty(&TermiosConfig::default()).unwrap();
+        let mut console = Console::new((10, 3));
+        let mut session = Session::new();
+        session.pty_out = console.typed_bytes(b"ls\necho");
+        // line discipline echoes CR as CR LF
+        for _ in 0..100 {
+            session
+                .pump_pty(&mut console, &mut pty, 1024, false)
+                .unwrap();
+            if console.get_cursor() == (4, 1) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(console.snapshot(), "ls\necho\n");
+        assert_eq!(console.get_cursor(), (4, 1));
+        let mut buf = [0; 16];
+        let len = unistd::read(pty.slave, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ls\n");
+    }
+
+    #[test]
+    fn guard_kills_child_ignoring_hangup() {
+        use nix::sys::signal::kill;
+        let command = ["sh", "-c", "trap '' HUP; echo; exec sleep 30"].map(String::from);
+        let command = ChildCommand::new(&command, (80, 24));
+        let _lock = FORK_LOCK.lock().unwrap();
+        let (read, write) = unistd::pipe().unwrap();
+        let child = match unistd::fork().unwrap() {
+            unistd::ForkResult::Child => {
+                if unistd::dup2(write, 1).is_err() {
+                    command.fail();
+                }
+                command.exec()
+            }
+            unistd::ForkResult::Parent { child } => child,
+        };
+        unistd::close(write).unwrap();
+        // trap is set once the line is written
+        let mut buf = [0; 1];
+        assert_eq!(unistd::read(read, &mut buf), Ok(1));
+        unistd::close(read).unwrap();
+        let start = std::time::Instant::now();
+        drop(ChildGuard::new(child));
+        assert!(start.elapsed() < REAP_TIMEOUT * 4);
+        assert!(kill(child, None).is_err());
+    }
+
+    #[test]
+    fn echo_round_trip_through_shell() {
+        use nix::sys::wait::{waitpid, WaitStatus};
+        let mut pty = openpty(&TermiosConfig::default()).unwrap();
+        let mut console = Console::new((40, 10));
+        let command = ChildCommand::new(&["sh".to_string()], console.get_size());
+        let _lock = FORK_LOCK.lock().unwrap();
+        let child = match unistd::fork().unwrap() {
+            unistd::ForkResult::Child => exec_child(&mut pty, &command),
+            unistd::ForkResult::Parent { child } => child,
+        };
+        pty.close_slave();
+        let mut session = Session::new();
+        let mut pump_until =
+            |console: &mut Console,
+             session: &mut Session,
+             done: &dyn Fn(&Console, &Session) -> bool| {
+                for _ in 0..500 {
+                    session.pump_pty(console, &mut pty, 1024, false).unwrap();
+                    if done(console, session) {
+                        return;
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                panic!("timeout, screen {:?}", console.snapshot());
+            };
+        // typed after prompt, so that output is on its own line
+        pump_until(&mut console, &mut session, &|console, _| {
+            console.get_cursor().0 > 0
+        });
+        session.pty_out = console.typed_bytes(b"echo hi\n");
+        let has_output =
+            |console: &Console, _: &Session| console.snapshot().lines().any(|line| line == "hi");
+        pump_until(&mut console, &mut session, &has_output);
+        let snapshot = console.snapshot();
+        let lines: Vec<&str> = snapshot.lines().collect();
+        let output = lines.iter().position(|line| *line == "hi").unwrap();
+        assert!(output > 0);
+        assert!(lines[output - 1].ends_with("echo hi"));
+        session.pty_out = console.typed_bytes(b"exit 4\n");
+        pump_until(&mut console, &mut session, &|_, session| {
+            session.pty_out.is_empty()
+        });
+        assert_eq!(waitpid(child, None), Ok(WaitStatus::Exited(child, 4)));
+    }
 }
@@ -0,0 +1,378 @@
+// vt500 style escape sequence parser
+// see https://vt100.net/emu/dec_ansi_parser
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Print(u8),
+    // C0 control
+    Execute(u8),
+    EscDispatch {
+        intermediate: Vec<u8>,
+        final_byte: u8,
+    },
+    // private markers are kept in param
+    CsiDispatch {
+        param: Vec<u8>,
+        intermediate: Vec<u8>,
+        final_byte: u8,
+    },
+    OscDispatch(Vec<u8>),
+    // params, intermediates, final byte and data are not split
+    DcsDispatch(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    CsiIgnore,
+    OscString,
+    DcsString,
+    // ESC in tmux passthrough, doubled ESC is data
+    DcsEscape,
+    // too long string, or SOS, PM and APC strings,
+    // skipped until terminated
+    StringIgnore,
+}
+
+pub struct Parser {
+    state: State,
+    param: Vec<u8>,
+    intermediate: Vec<u8>,
+    osc: Vec<u8>,
+    dcs: Vec<u8>,
+    // longer OSC and DCS strings are aborted
+    pub max_string_len: usize,
+    // accept 0x9b as CSI, a utf-8 continuation byte otherwise
+    pub c1_controls: bool,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            param: Vec::new(),
+            intermediate: Vec::new(),
+            osc: Vec::new(),
+            dcs: Vec::new(),
+            max_string_len: 1 << 20,
+            c1_controls: false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.param.clear();
+        self.intermediate.clear();
+    }
+
+    pub fn advance(&mut self, ch: u8) -> Option<Action> {
+        // transitions from anywhere
+        match ch {
+            0x18 | 0x1a => {
+                self.state = State::Ground;
+                return Some(Action::Execute(ch));
+            }
+            // tmux passthrough, string ends at ESC not followed by ESC
+            0x1b if self.state == State::DcsString && self.dcs.starts_with(b"tmux;") => {
+                self.state = State::DcsEscape;
+                return None;
+            }
+            0x1b if self.state == State::DcsEscape => {
+                self.dcs.push(ch);
+                self.state = State::DcsString;
+                return None;
+            }
+            0x1b => {
+                let state = self.state;
+                self.state = State::Escape;
+                self.clear();
+                return match state {
+                    State::OscString => Some(Action::OscDispatch(std::mem::take(&mut self.osc))),
+                    State::DcsString => Some(Action::DcsDispatch(std::mem::take(&mut self.dcs))),
+                    _ => None,
+                };
+            }
+            0x9b if self.c1_controls => {
+                self.state = State::CsiEntry;
+                self.clear();
+                return None;
+            }
+            0x90 if self.c1_controls => {
+                self.dcs.clear();
+                self.state = State::DcsString;
+                return None;
+            }
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => match ch {
+                0x00..=0x1f => Some(Action::Execute(ch)),
+                _ => Some(Action::Print(ch)),
+            },
+            State::Escape => match ch {
+                0x00..=0x1f => Some(Action::Execute(ch)),
+                0x20..=0x2f => {
+                    self.intermediate.push(ch);
+                    self.state = State::EscapeIntermediate;
+                    None
+                }
+                b'[' => {
+                    self.state = State::CsiEntry;
+                    None
+                }
+                b']' => {
+                    self.osc.clear();
+                    self.state = State::OscString;
+                    None
+                }
+                b'P' => {
+                    self.dcs.clear();
+                    self.state = State::DcsString;
+                    None
+                }
+                // SOS, PM and APC, e.g. kitty graphics
+                b'X' | b'^' | b'_' => {
+                    println!("Ignored string of ESC {}", char::from(ch));
+                    self.state = State::StringIgnore;
+                    None
+                }
+                0x30..=0x7e => self.esc_dispatch(ch),
+                _ => None,
+            },
+            State::EscapeIntermediate => match ch {
+                0x00..=0x1f => Some(Action::Execute(ch)),
+                0x20..=0x2f => {
+                    self.intermediate.push(ch);
+                    None
+                }
+                0x30..=0x7e => self.esc_dispatch(ch),
+                _ => None,
+            },
+            State::CsiEntry => match ch {
+                0x00..=0x1f => Some(Action::Execute(ch)),
+                0x20..=0x2f => {
+                    self.intermediate.push(ch);
+                    self.state = State::CsiIntermediate;
+                    None
+                }
+                // digits, ':', ';' and private markers
+                0x30..=0x3f => {
+                    self.param.push(ch);
+                    self.state = State::CsiParam;
+                    None
+                }
+                0x40..=0x7e => self.csi_dispatch(ch),
+                _ => None,
+            },
+            State::CsiParam => match ch {
+                0x00..=0x1f => Some(Action::Execute(ch)),
+                0x20..=0x2f => {
+                    self.intermediate.push(ch);
+                    self.state = State::CsiIntermediate;
+                    None
+                }
+                0x30..=0x3b => {
+                    self.param.push(ch);
+                    None
+                }
+                0x3c..=0x3f => {
+                    self.state = State::CsiIgnore;
+                    None
+                }
+                0x40..=0x7e => self.csi_dispatch(ch),
+                _ => None,
+            },
+            State::CsiIntermediate => match ch {
+                0x00..=0x1f => Some(Action::Execute(ch)),
+                0x20..=0x2f => {
+                    self.intermediate.push(ch);
+                    None
+                }
+                0x30..=0x3f => {
+                    self.state = State::CsiIgnore;
+                    None
+                }
+                0x40..=0x7e => self.csi_dispatch(ch),
+                _ => None,
+            },
+            State::CsiIgnore => match ch {
+                0x00..=0x1f => Some(Action::Execute(ch)),
+                0x40..=0x7e => {
+                    self.state = State::Ground;
+                    None
+                }
+                _ => None,
+            },
+            State::OscString => match ch {
+                0x07 => {
+                    self.state = State::Ground;
+                    Some(Action::OscDispatch(std::mem::take(&mut self.osc)))
+                }
+                0x00..=0x1f => None,
+                _ => {
+                    if self.osc.len() >= self.max_string_len {
+                        println!("OSC string longer than {}, aborted", self.max_string_len);
+                        self.osc = Vec::new();
+                        self.state = State::StringIgnore;
+                    } else {
+                        self.osc.push(ch);
+                    }
+                    None
+                }
+            },
+            // terminated by ST only
+            State::DcsString => match ch {
+                0x00..=0x1f => None,
+                _ => {
+                    if self.dcs.len() >= self.max_string_len {
+                        println!("DCS string longer than {}, aborted", self.max_string_len);
+                        self.dcs = Vec::new();
+                        self.state = State::StringIgnore;
+                    } else {
+                        self.dcs.push(ch);
+                    }
+                    None
+                }
+            },
+            State::DcsEscape => {
+                if ch == b'\\' {
+                    self.state = State::Ground;
+                    return Some(Action::DcsDispatch(std::mem::take(&mut self.dcs)));
+                }
+                // not terminated by ST, string is dropped
+                self.dcs.clear();
+                self.state = State::Escape;
+                self.clear();
+                self.advance(ch)
+            }
+            // ESC is handled above
+            State::StringIgnore => match ch {
+                0x07 => {
+                    self.state = State::Ground;
+                    None
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn esc_dispatch(&mut self, ch: u8) -> Option<Action> {
+        self.state = State::Ground;
+        Some(Action::EscDispatch {
+            intermediate: std::mem::take(&mut self.intermediate),
+            final_byte: ch,
+        })
+    }
+
+    fn csi_dispatch(&mut self, ch: u8) -> Option<Action> {
+        self.state = State::Ground;
+        Some(Action::CsiDispatch {
+            param: std::mem::take(&mut self.param),
+            intermediate: std::mem::take(&mut self.intermediate),
+            final_byte: ch,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(parser: &mut Parser, bytes: &[u8]) -> Vec<Action> {
+        bytes.iter().filter_map(|&ch| parser.advance(ch)).collect()
+    }
+
+    #[test]
+    fn c1_csi() {
+        let mut parser = Parser::new();
+        let expected = parse(&mut parser, b"\x1b[31m");
+        assert_eq!(parse(&mut parser, b"\x9b31m")[0], Action::Print(0x9b));
+        parser.c1_controls = true;
+        assert_eq!(parse(&mut parser, b"\x9b31m"), expected);
+    }
+
+    #[test]
+    fn tmux_passthrough() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse(&mut parser, b"\x1bPtmux;\x1b\x1b[2J\x1b\\a"),
+            vec![
+                Action::DcsDispatch(b"tmux;\x1b[2J".to_vec()),
+                Action::Print(b'a'),
+            ]
+        );
+    }
+
+    #[test]
+    fn state_transitions() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse(&mut parser, b"a\x1b(B\x1b[?1;2h\x1b[ q\x1b]0;t\x07"),
+            vec![
+                Action::Print(b'a'),
+                Action::EscDispatch {
+                    intermediate: b"(".to_vec(),
+                    final_byte: b'B',
+                },
+                Action::CsiDispatch {
+                    param: b"?1;2".to_vec(),
+                    intermediate: Vec::new(),
+                    final_byte: b'h',
+                },
+                Action::CsiDispatch {
+                    param: Vec::new(),
+                    intermediate: b" ".to_vec(),
+                    final_byte: b'q',
+                },
+                Action::OscDispatch(b"0;t".to_vec()),
+            ]
+        );
+        assert_eq!(parser.state, State::Ground);
+        // C0 inside CSI is executed, CAN aborts
+        assert_eq!(
+            parse(&mut parser, b"\x1b[1\n\x18b"),
+            vec![
+                Action::Execute(b'\n'),
+                Action::Execute(0x18),
+                Action::Print(b'b')
+            ]
+        );
+        // private marker after params is ignored until final byte
+        assert_eq!(parse(&mut parser, b"\x1b[1?2hc"), vec![Action::Print(b'c')]);
+        // ESC ends DCS string
+        assert_eq!(
+            parse(&mut parser, b"\x1bP$qm\x1b\\"),
+            vec![
+                Action::DcsDispatch(b"$qm".to_vec()),
+                Action::EscDispatch {
+                    intermediate: Vec::new(),
+                    final_byte: b'\\',
+                },
+            ]
+        );
+        // APC is skipped
+        assert_eq!(
+            parse(&mut parser, b"\x1b_Gx\x1b\\d")[1],
+            Action::Print(b'd')
+        );
+    }
+
+    #[test]
+    fn long_osc_aborted() {
+        let mut parser = Parser::new();
+        parser.max_string_len = 8;
+        assert_eq!(
+            parse(&mut parser, b"\x1b]2;0123456789\x07a"),
+            vec![Action::Print(b'a')]
+        );
+        assert_eq!(
+            parse(&mut parser, b"\x1b]2;abc\x07"),
+            vec![Action::OscDispatch(b"2;abc".to_vec())]
+        );
+    }
+}
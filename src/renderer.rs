@@ -0,0 +1,219 @@
+// frontends showing frames rendered by Console
+
+use sdl2::clipboard::ClipboardUtil;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::WindowContext;
+
+pub trait Renderer {
+    // RGB24 data of frame size, kept until next update
+    fn update_frame(&mut self, data: &[u8], size: (u32, u32));
+    // show last updated frame
+    fn present(&mut self) -> Result<(), String>;
+    fn set_title(&mut self, title: &str) -> Result<(), String>;
+    fn clipboard(&self) -> Option<String>;
+    fn set_clipboard(&mut self, text: &str);
+    // size of window showing frames, in same unit as mouse events
+    fn size(&self) -> (u32, u32);
+    // size in pixels, larger than size on hidpi
+    fn drawable_size(&self) -> (u32, u32);
+}
+
+// largest rect with canvas aspect ratio, centered in window
+pub fn letterbox(canvas_size: (u32, u32), window_size: (u32, u32)) -> Rect {
+    let scale = (window_size.0 as f32 / canvas_size.0 as f32)
+        .min(window_size.1 as f32 / canvas_size.1 as f32);
+    let w = (canvas_size.0 as f32 * scale) as u32;
+    let h = (canvas_size.1 as f32 * scale) as u32;
+    Rect::new(
+        window_size.0.saturating_sub(w) as i32 / 2,
+        window_size.1.saturating_sub(h) as i32 / 2,
+        w,
+        h,
+    )
+}
+
+// bytes per row of RGB24 frame
+fn pitch(width: u32) -> usize {
+    width as usize * 3
+}
+
+pub struct SdlRenderer<'a> {
+    canvas: WindowCanvas,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    texture: Texture<'a>,
+    frame_size: (u32, u32),
+    clipboard: ClipboardUtil,
+}
+
+impl<'a> SdlRenderer<'a> {
+    pub fn new(
+        canvas: WindowCanvas,
+        texture_creator: &'a TextureCreator<WindowContext>,
+        clipboard: ClipboardUtil,
+        frame_size: (u32, u32),
+    ) -> SdlRenderer<'a> {
+        let texture = texture_creator
+            .create_texture_static(Some(PixelFormatEnum::RGB24), frame_size.0, frame_size.1)
+            .unwrap();
+        SdlRenderer {
+            canvas,
+            texture_creator,
+            texture,
+            frame_size,
+            clipboard,
+        }
+    }
+}
+
+impl<'a> Renderer for SdlRenderer<'a> {
+    // texture is recreated when console is resized
+    fn update_frame(&mut self, data: &[u8], size: (u32, u32)) {
+        if size != self.frame_size {
+            self.texture = self
+                .texture_creator
+                .create_texture_static(Some(PixelFormatEnum::RGB24), size.0, size.1)
+                .unwrap();
+            self.frame_size = size;
+        }
+        let pitch = pitch(size.0);
+        debug_assert_eq!(pitch * size.1 as usize, data.len());
+        self.texture.update(None, data, pitch).unwrap();
+    }
+
+    fn present(&mut self) -> Result<(), String> {
+        self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+        self.canvas.clear();
+        let rect = letterbox(self.frame_size, self.drawable_size());
+        self.canvas.copy(&self.texture, None, rect)?;
+        self.canvas.present();
+        Ok(())
+    }
+
+    fn set_title(&mut self, title: &str) -> Result<(), String> {
+        self.canvas
+            .window_mut()
+            .set_title(title)
+            .map_err(|e| e.to_string())
+    }
+
+    fn clipboard(&self) -> Option<String> {
+        self.clipboard.clipboard_text().ok()
+    }
+
+    fn set_clipboard(&mut self, text: &str) {
+        self.clipboard.set_clipboard_text(text).ok();
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.canvas.window().size()
+    }
+
+    fn drawable_size(&self) -> (u32, u32) {
+        self.canvas.output_size().unwrap_or_else(|_| self.size())
+    }
+}
+
+// headless, present saves frame as png
+pub struct PngRenderer {
+    path: String,
+    frame: Vec<u8>,
+    frame_size: (u32, u32),
+}
+
+impl PngRenderer {
+    pub fn new(path: &str) -> PngRenderer {
+        PngRenderer {
+            path: path.to_string(),
+            frame: Vec::new(),
+            frame_size: (0, 0),
+        }
+    }
+}
+
+impl Renderer for PngRenderer {
+    fn update_frame(&mut self, data: &[u8], size: (u32, u32)) {
+        self.frame = data.to_vec();
+        self.frame_size = size;
+    }
+
+    fn present(&mut self) -> Result<(), String> {
+        image::save_buffer(
+            &self.path,
+            &self.frame,
+            self.frame_size.0,
+            self.frame_size.1,
+            image::ColorType::Rgb8,
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    fn set_title(&mut self, _: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn clipboard(&self) -> Option<String> {
+        None
+    }
+
+    fn set_clipboard(&mut self, _: &str) {}
+
+    fn size(&self) -> (u32, u32) {
+        self.frame_size
+    }
+
+    fn drawable_size(&self) -> (u32, u32) {
+        self.frame_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::Console;
+
+    #[test]
+    fn frame_pitch_after_resize() {
+        let mut console = Console::new((10, 2));
+        let mut renderer = PngRenderer::new("");
+        console.draw(&mut renderer);
+        console.resize((13, 3));
+        console.draw(&mut renderer);
+        assert_eq!(renderer.frame_size, console.get_canvas_size());
+        let (width, height) = renderer.frame_size;
+        assert_eq!(renderer.frame.len(), pitch(width) * height as usize);
+    }
+
+    #[test]
+    fn letterbox_keeps_aspect() {
+        // wider window, bars left and right
+        let rect = letterbox((150, 40), (600, 80));
+        assert_eq!((rect.width(), rect.height()), (300, 80));
+        assert_eq!((rect.x(), rect.y()), (150, 0));
+        // taller window, bars top and bottom
+        let rect = letterbox((150, 40), (150, 100));
+        assert_eq!((rect.width(), rect.height()), (150, 40));
+        assert_eq!((rect.x(), rect.y()), (0, 30));
+    }
+
+    #[test]
+    fn headless_frame_matches_screen() {
+        let mut console = Console::new((4, 2));
+        for &ch in b"\x1b[41m \x1b[0m\x1b[?25l" {
+            console.put_char(ch);
+        }
+        let path = std::env::temp_dir().join(format!("fsdterm-{}.png", std::process::id()));
+        let mut renderer = PngRenderer::new(path.to_str().unwrap());
+        console.draw(&mut renderer);
+        assert_eq!(renderer.frame[..3], [205, 0, 0]);
+        let (width, _) = renderer.frame_size;
+        let next_cell = pitch(width) * 2 - 3;
+        assert_eq!(renderer.frame[next_cell..next_cell + 3], [0, 0, 0]);
+        renderer.present().unwrap();
+        let image = image::open(&path).unwrap().to_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.dimensions(), renderer.frame_size);
+        assert_eq!(image.into_raw(), renderer.frame);
+    }
+}
@@ -1,7 +1,77 @@
+use std::collections::VecDeque;
+
+use crate::cell::{Attr, Cell, Color, UnderlineStyle};
+use crate::sixel::SixelImage;
+
+// oldest image is dropped when more are added
+const MAX_IMAGES: usize = 8;
+
+// OSC 133 semantic prompt marks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PromptMark {
+    Prompt,
+    Command,
+    Output,
+    End,
+}
+
+// line scrolled out of top of screen
+#[derive(Clone)]
+struct Line {
+    cells: Vec<Cell>,
+    marks: Vec<(i32, PromptMark)>,
+}
+
 pub struct ScreenBuffer {
     size: (i32, i32),
     cursor: (i32, i32),
-    pub buffer: Vec<u8>,
+    // scroll region, top and bottom line inclusive
+    margin: (i32, i32),
+    // current SGR attributes, applied to new chars
+    attr: Attr,
+    // DECSC, cursor and attributes
+    saved_cursor: Option<((i32, i32), Attr)>,
+    pub buffer: Vec<Cell>,
+    // line is continued by autowrap on the next line
+    wrapped: Vec<bool>,
+    // DECAWM
+    pub autowrap: bool,
+    // IRM, printed chars shift rest of line right
+    pub insert_mode: bool,
+    // background color erase, cleared cells take current bg
+    pub bce: bool,
+    // DECLRMM, enables lr_margin
+    lr_margin_mode: bool,
+    // left and right column inclusive, set by DECSLRM
+    lr_margin: (i32, i32),
+    tab_stops: Vec<bool>,
+    // columns between default tab stops
+    tab_width: i32,
+    // prompt marks of each line with column
+    marks: Vec<Vec<(i32, PromptMark)>>,
+    scrollback: VecDeque<Line>,
+    // max lines in scrollback, 0 to disable
+    pub scrollback_limit: usize,
+    // lines scrolled out of top of screen so far
+    scrolled: u64,
+    // lines scrolled back from bottom
+    view_offset: i32,
+    // chars written at last column without autowrap, from that column
+    overflow: Vec<Vec<Cell>>,
+    // cursor position in overflow, None if cursor moved since
+    edge_x: Option<usize>,
+    // columns scrolled right, only used without autowrap
+    h_offset: i32,
+    // images with top left cell and rows covered
+    images: Vec<((i32, i32), i32, SixelImage)>,
+    // cells changed since last take_damage
+    dirty: Vec<bool>,
+    // cursor at last take_damage
+    damage_cursor: (i32, i32),
+}
+
+fn default_tab_stops(range: std::ops::Range<i32>, width: i32) -> impl Iterator<Item = bool> {
+    range.map(move |x| x % width == 0 && x > 0)
 }
 
 impl ScreenBuffer {
@@ -9,41 +79,501 @@ impl ScreenBuffer {
         ScreenBuffer {
             size,
             cursor: (0, 0),
-            buffer: vec![0; (size.0 * size.1) as usize],
+            margin: (0, size.1 - 1),
+            attr: Attr::default(),
+            saved_cursor: None,
+            buffer: vec![Cell::default(); (size.0 * size.1) as usize],
+            wrapped: vec![false; size.1 as usize],
+            autowrap: true,
+            insert_mode: false,
+            bce: true,
+            lr_margin_mode: false,
+            lr_margin: (0, size.0 - 1),
+            tab_stops: default_tab_stops(0..size.0, 8).collect(),
+            tab_width: 8,
+            marks: vec![Vec::new(); size.1 as usize],
+            scrollback: VecDeque::new(),
+            scrollback_limit: 10000,
+            scrolled: 0,
+            view_offset: 0,
+            overflow: vec![Vec::new(); size.1 as usize],
+            edge_x: None,
+            h_offset: 0,
+            images: Vec::new(),
+            dirty: vec![true; (size.0 * size.1) as usize],
+            damage_cursor: (0, 0),
+        }
+    }
+
+    // reflow soft wrapped lines to new width, reset scroll region
+    pub fn resize(&mut self, size: (i32, i32)) {
+        // join wrapped rows into logical lines, cursor is (line, offset)
+        let mut lines: Vec<Vec<Cell>> = Vec::new();
+        // marks of each logical line with offset
+        let mut line_marks: Vec<Vec<(i32, PromptMark)>> = Vec::new();
+        let mut cursor = (0, 0);
+        let mut new_line = true;
+        for y in 0..self.size.1 {
+            if new_line {
+                lines.push(Vec::new());
+                line_marks.push(Vec::new());
+            }
+            let id = lines.len() - 1;
+            let offset = lines[id].len() as i32;
+            if y == self.cursor.1 {
+                cursor = (id, offset + self.cursor.0);
+            }
+            for (x, mark) in self.marks[y as usize].iter() {
+                line_marks[id].push((offset + x, *mark));
+            }
+            lines[id].extend_from_slice(
+                &self.buffer[(y * self.size.0) as usize..((y + 1) * self.size.0) as usize],
+            );
+            new_line = !self.wrapped[y as usize];
+        }
+
+        // split logical lines by new width
+        let mut buffer = Vec::new();
+        let mut wrapped = Vec::new();
+        let mut marks: Vec<Vec<(i32, PromptMark)>> = Vec::new();
+        let mut new_cursor = (0, 0);
+        for (id, line) in lines.iter_mut().enumerate() {
+            while let Some(cell) = line.last() {
+                if (cell.ch != 0 && cell.ch != b' ') || cell.attr != Attr::default() {
+                    break;
+                }
+                line.pop();
+            }
+            let mut rows = ((line.len() as i32 + size.0 - 1) / size.0).max(1);
+            if id == cursor.0 {
+                new_cursor = (cursor.1 % size.0, wrapped.len() as i32 + cursor.1 / size.0);
+                rows = rows.max(cursor.1 / size.0 + 1);
+            }
+            for (offset, _) in line_marks[id].iter() {
+                rows = rows.max(offset / size.0 + 1);
+            }
+            let first = wrapped.len();
+            for row in 0..rows {
+                for x in 0..size.0 {
+                    let cell = line.get((x + row * size.0) as usize);
+                    buffer.push(cell.copied().unwrap_or_default());
+                }
+                wrapped.push(row < rows - 1);
+                marks.push(Vec::new());
+            }
+            for (offset, mark) in line_marks[id].iter() {
+                marks[first + (offset / size.0) as usize].push((offset % size.0, *mark));
+            }
+        }
+
+        // drop rows below cursor first, then rows on top
+        let total = wrapped.len() as i32;
+        let below = (total - size.1).min(total - 1 - new_cursor.1).max(0);
+        let total = total - below;
+        let top = (total - size.1).max(0);
+        buffer.truncate((total * size.0) as usize);
+        wrapped.truncate(total as usize);
+        marks.truncate(total as usize);
+        // rows on top go to scrollback, joined into logical lines
+        if self.scrollback_limit > 0 {
+            let mut cells = Vec::new();
+            let mut line_marks = Vec::new();
+            let rows = buffer
+                .chunks(size.0 as usize)
+                .zip(wrapped.iter())
+                .zip(marks.iter());
+            for (row, ((line, &wrap), row_marks)) in rows.take(top as usize).enumerate() {
+                let offset = cells.len() as i32;
+                line_marks.extend(row_marks.iter().map(|(x, mark)| (offset + x, *mark)));
+                cells.extend_from_slice(line);
+                if !wrap || row + 1 == top as usize {
+                    self.scrollback.push_back(Line {
+                        cells: std::mem::take(&mut cells),
+                        marks: std::mem::take(&mut line_marks),
+                    });
+                }
+            }
+            let excess = self.scrollback.len().saturating_sub(self.scrollback_limit);
+            self.scrollback.drain(..excess);
+        }
+        buffer.drain(..(top * size.0) as usize);
+        wrapped.drain(..top as usize);
+        marks.drain(..top as usize);
+        new_cursor.1 -= top;
+        buffer.resize((size.0 * size.1) as usize, Cell::default());
+        wrapped.resize(size.1 as usize, false);
+        marks.resize(size.1 as usize, Vec::new());
+
+        // columns out of old width get default tab stops
+        self.tab_stops.truncate(size.0 as usize);
+        self.tab_stops.extend(default_tab_stops(
+            self.size.0.min(size.0)..size.0,
+            self.tab_width,
+        ));
+
+        // overflow and images are not reflowed
+        self.images.clear();
+        self.marks = marks;
+        self.overflow = vec![Vec::new(); size.1 as usize];
+        self.h_offset = 0;
+        self.view_offset = 0;
+        self.dirty = vec![true; (size.0 * size.1) as usize];
+        self.size = size;
+        self.buffer = buffer;
+        self.wrapped = wrapped;
+        self.margin = (0, size.1 - 1);
+        self.lr_margin = (0, size.0 - 1);
+        self.move_cursor(new_cursor.0, new_cursor.1, true);
+        // saved cursor is restored after leaving alternate screen
+        if let Some((cursor, _)) = self.saved_cursor.as_mut() {
+            cursor.0 = cursor.0.min(size.0 - 1);
+            cursor.1 = cursor.1.min(size.1 - 1);
+        }
+    }
+
+    // left and right margin, full width without DECLRMM
+    fn h_margin(&self) -> (i32, i32) {
+        if self.lr_margin_mode {
+            self.lr_margin
+        } else {
+            (0, self.size.0 - 1)
         }
     }
 
+    // wrap at right margin to left margin if cursor is inside margins
     fn cursor_inc(&mut self) {
-        if self.cursor.0 < self.size.0 - 1 {
+        let (left, right) = self.h_margin();
+        let inside = left <= self.cursor.0 && self.cursor.0 <= right;
+        let right = if inside { right } else { self.size.0 - 1 };
+        if self.cursor.0 < right {
             self.cursor.0 += 1;
-        } else {
+        } else if self.autowrap {
+            self.wrapped[self.cursor.1 as usize] = true;
             self.cursor_newline();
+            self.cursor.0 = if inside { left } else { 0 };
         }
     }
 
+    // LF, column is kept
     fn cursor_newline(&mut self) {
-        self.cursor.0 = 0;
-        if self.cursor.1 < self.size.1 - 1 {
+        if self.cursor.1 == self.margin.1 {
+            self.scroll_up(1);
+        } else if self.cursor.1 < self.size.1 - 1 {
             self.cursor.1 += 1;
+        }
+    }
+
+    fn set_cell(&mut self, index: i32, cell: Cell) {
+        self.buffer[index as usize] = cell;
+        self.dirty[index as usize] = true;
+    }
+
+    // attributes of erased and cleared cells
+    fn erased_attr(&self) -> Attr {
+        if self.bce {
+            Attr {
+                bg: self.attr.bg,
+                ..Attr::default()
+            }
         } else {
-            self.scroll_up();
-            self.clear_line();
+            Attr::default()
         }
     }
 
-    fn clear_line(&mut self) {
+    fn clear_line(&mut self, y: i32) {
         for x in 0..self.size.0 {
-            self.buffer[(x + self.cursor.1 * self.size.0) as usize] = 0;
+            self.set_cell(x + y * self.size.0, Cell::new(0, self.erased_attr()));
         }
+        self.wrapped[y as usize] = false;
+        self.marks[y as usize].clear();
+        self.overflow[y as usize].clear();
     }
 
-    // does not move cursor
-    fn scroll_up(&mut self) {
+    fn copy_line(&mut self, from: i32, to: i32) {
         for x in 0..self.size.0 {
-            for y in 0..self.size.1 - 1 {
-                self.buffer[(x + y * self.size.0) as usize] =
-                    self.buffer[(x + (y + 1) * self.size.0) as usize];
+            self.set_cell(
+                x + to * self.size.0,
+                self.buffer[(x + from * self.size.0) as usize],
+            );
+        }
+        self.wrapped[to as usize] = self.wrapped[from as usize];
+        self.marks[to as usize] = self.marks[from as usize].clone();
+        self.overflow[to as usize] = self.overflow[from as usize].clone();
+    }
+
+    // line with overflow in place of last column
+    fn logical_line(&self, y: i32) -> Vec<Cell> {
+        let start = (y * self.size.0) as usize;
+        let overflow = &self.overflow[y as usize];
+        if overflow.is_empty() {
+            return self.buffer[start..start + self.size.0 as usize].to_vec();
+        }
+        let mut line = self.buffer[start..start + self.size.0 as usize - 1].to_vec();
+        line.extend_from_slice(overflow);
+        line
+    }
+
+    fn push_scrollback(&mut self, y: i32) {
+        if self.scrollback_limit == 0 {
+            return;
+        }
+        self.scrollback.push_back(Line {
+            cells: self.logical_line(y),
+            marks: self.marks[y as usize].clone(),
+        });
+        if self.scrollback.len() > self.scrollback_limit {
+            self.scrollback.pop_front();
+        } else if self.view_offset > 0 {
+            // keep viewed lines in place
+            self.view_offset += 1;
+        }
+    }
+
+    // part of line between left and right column inclusive
+    fn copy_cells(&mut self, from: i32, to: i32, left: i32, right: i32) {
+        for x in left..=right {
+            self.set_cell(
+                x + to * self.size.0,
+                self.buffer[(x + from * self.size.0) as usize],
+            );
+        }
+    }
+
+    fn clear_cells(&mut self, y: i32, left: i32, right: i32) {
+        for x in left..=right {
+            self.set_cell(x + y * self.size.0, Cell::new(0, self.erased_attr()));
+        }
+    }
+
+    // does not move cursor, only lines inside margin are affected
+    // lines scrolled out of a full width top margin go to scrollback
+    pub fn scroll_up(&mut self, n: i32) {
+        let (top, bottom) = self.margin;
+        let n = n.min(bottom - top + 1);
+        let (left, right) = self.h_margin();
+        if (left, right) != (0, self.size.0 - 1) {
+            for y in top..=bottom - n {
+                self.copy_cells(y + n, y, left, right);
+            }
+            for y in bottom - n + 1..=bottom {
+                self.clear_cells(y, left, right);
+            }
+            return;
+        }
+        if top == 0 {
+            for y in 0..n {
+                self.push_scrollback(y);
+            }
+            self.scrolled += n as u64;
+        }
+        if (top, bottom) == (0, self.size.1 - 1) {
+            self.shift_images(-n);
+        }
+        for y in top..=bottom - n {
+            self.copy_line(y + n, y);
+        }
+        for y in bottom - n + 1..=bottom {
+            self.clear_line(y);
+        }
+    }
+
+    // does not move cursor, only lines inside margin are affected
+    pub fn scroll_down(&mut self, n: i32) {
+        let (top, bottom) = self.margin;
+        let n = n.min(bottom - top + 1);
+        let (left, right) = self.h_margin();
+        if (left, right) != (0, self.size.0 - 1) {
+            for y in (top + n..=bottom).rev() {
+                self.copy_cells(y - n, y, left, right);
+            }
+            for y in top..top + n {
+                self.clear_cells(y, left, right);
+            }
+            return;
+        }
+        for y in (top + n..=bottom).rev() {
+            self.copy_line(y - n, y);
+        }
+        if (top, bottom) == (0, self.size.1 - 1) {
+            self.shift_images(n);
+        }
+        for y in top..top + n {
+            self.clear_line(y);
+        }
+    }
+
+    // drop images moved out of screen
+    fn shift_images(&mut self, n: i32) {
+        let height = self.size.1;
+        for ((_, y), _, _) in self.images.iter_mut() {
+            *y += n;
+        }
+        self.images
+            .retain(|((_, y), rows, _)| *y + *rows > 0 && *y < height);
+    }
+
+    // image at cursor, cursor moves to next line after it
+    pub fn add_image(&mut self, image: SixelImage, rows: i32) {
+        if self.images.len() >= MAX_IMAGES {
+            self.images.remove(0);
+        }
+        self.images.push((self.cursor, rows, image));
+        for _ in 0..rows {
+            self.set_char(b'\n', true);
+        }
+        self.set_char(b'\r', true);
+    }
+
+    pub fn images(&self) -> &[((i32, i32), i32, SixelImage)] {
+        &self.images
+    }
+
+    pub fn get_margin(&self) -> (i32, i32) {
+        self.margin
+    }
+
+    // DECSTR, keep screen content
+    pub fn soft_reset(&mut self) {
+        self.attr = Attr::default();
+        self.margin = (0, self.size.1 - 1);
+        self.lr_margin = (0, self.size.0 - 1);
+        self.insert_mode = false;
+    }
+
+    // RI, cursor up or scroll down at top margin
+    pub fn reverse_index(&mut self) {
+        self.edge_x = None;
+        if self.cursor.1 == self.margin.0 {
+            self.scroll_down(1);
+        } else if self.cursor.1 > 0 {
+            self.cursor.1 -= 1;
+        }
+    }
+
+    // DECSTBM, invalid region resets to full screen
+    pub fn set_margin(&mut self, top: i32, bottom: i32) {
+        if top < bottom && bottom < self.size.1 {
+            self.margin = (top, bottom);
+        } else {
+            self.margin = (0, self.size.1 - 1);
+        }
+        self.move_cursor(0, 0, true);
+    }
+
+    // DECLRMM, margins are reset on both set and reset
+    pub fn set_lr_margin_mode(&mut self, value: bool) {
+        self.lr_margin_mode = value;
+        self.lr_margin = (0, self.size.0 - 1);
+    }
+
+    pub fn lr_margin_mode(&self) -> bool {
+        self.lr_margin_mode
+    }
+
+    // DECSLRM, invalid region resets to full width
+    pub fn set_lr_margin(&mut self, left: i32, right: i32) {
+        if left < right && right < self.size.0 {
+            self.lr_margin = (left, right);
+        } else {
+            self.lr_margin = (0, self.size.0 - 1);
+        }
+        self.move_cursor(0, 0, true);
+    }
+
+    // ICH, cells right of cursor are shifted to right margin
+    pub fn insert_chars(&mut self, n: i32) {
+        let (left, right) = self.h_margin();
+        if self.cursor.0 < left || self.cursor.0 > right {
+            return;
+        }
+        let y = self.cursor.1;
+        let n = n.min(right - self.cursor.0 + 1);
+        for x in (self.cursor.0 + n..=right).rev() {
+            self.set_cell(
+                x + y * self.size.0,
+                self.buffer[(x - n + y * self.size.0) as usize],
+            );
+        }
+        self.clear_cells(y, self.cursor.0, self.cursor.0 + n - 1);
+    }
+
+    // DCH, blanks are filled from right margin
+    pub fn delete_chars(&mut self, n: i32) {
+        let (left, right) = self.h_margin();
+        if self.cursor.0 < left || self.cursor.0 > right {
+            return;
+        }
+        let y = self.cursor.1;
+        let n = n.min(right - self.cursor.0 + 1);
+        for x in self.cursor.0..=right - n {
+            self.set_cell(
+                x + y * self.size.0,
+                self.buffer[(x + n + y * self.size.0) as usize],
+            );
+        }
+        self.clear_cells(y, right - n + 1, right);
+    }
+
+    // ECH, margins are ignored
+    pub fn erase_chars(&mut self, n: i32) {
+        let end = (self.cursor.0 + n).min(self.size.0);
+        for x in self.cursor.0..end {
+            self.erase_cell(x + self.cursor.1 * self.size.0, false);
+        }
+    }
+
+    // replaces all tab stops, including ones set by HTS
+    pub fn set_tab_width(&mut self, width: i32) {
+        self.tab_width = width.max(1);
+        self.tab_stops = default_tab_stops(0..self.size.0, self.tab_width).collect();
+    }
+
+    // HT, stop at last column if no more tab stops
+    fn tab(&mut self) {
+        while self.cursor.0 < self.size.0 - 1 {
+            self.cursor.0 += 1;
+            if self.tab_stops[self.cursor.0 as usize] {
+                break;
+            }
+        }
+    }
+
+    // CHT, stops at last column
+    pub fn forward_tab(&mut self, n: i32) {
+        self.edge_x = None;
+        for _ in 0..n {
+            self.tab();
+        }
+    }
+
+    // CBT, stops at column 0
+    pub fn back_tab(&mut self, n: i32) {
+        self.edge_x = None;
+        for _ in 0..n {
+            while self.cursor.0 > 0 {
+                self.cursor.0 -= 1;
+                if self.tab_stops[self.cursor.0 as usize] {
+                    break;
+                }
+            }
+        }
+    }
+
+    // HTS
+    pub fn set_tab_stop(&mut self) {
+        self.tab_stops[self.cursor.0 as usize] = true;
+    }
+
+    // match csi definition
+    pub fn clear_tab_stop(&mut self, param: i32) {
+        if param == 0 {
+            self.tab_stops[self.cursor.0 as usize] = false;
+        } else if param == 3 {
+            for stop in self.tab_stops.iter_mut() {
+                *stop = false;
             }
+        } else {
+            println!("Unsupported TBC Param!")
         }
     }
 
@@ -55,12 +585,22 @@ impl ScreenBuffer {
     }
 
     pub fn set_char(&mut self, ch: u8, cursor_inc: bool) {
+        if ch < 0x20 {
+            self.edge_x = None;
+        }
         if ch == b'\n' {
+            self.wrapped[self.cursor.1 as usize] = false;
             self.cursor_newline();
             return;
         }
-        if ch == 7 {
-            println!("beep!");
+        if ch == b'\r' {
+            // left margin unless cursor is left of it
+            let left = self.h_margin().0;
+            self.cursor.0 = if self.cursor.0 >= left { left } else { 0 };
+            return;
+        }
+        if ch == b'\t' {
+            self.tab();
             return;
         }
         if ch == 8 {
@@ -68,13 +608,42 @@ impl ScreenBuffer {
             self.backspace();
             return;
         }
-        self.buffer[(self.cursor.0 + self.cursor.1 * self.size.0) as usize] = ch;
+        if self.insert_mode {
+            self.insert_chars(1);
+        }
+        self.set_cell(
+            self.cursor.0 + self.cursor.1 * self.size.0,
+            Cell::new(ch, self.attr),
+        );
+        if !self.autowrap && self.cursor.0 == self.size.0 - 1 {
+            // keep text beyond right edge for horizontal scrolling
+            let x = self.edge_x.map_or(0, |x| x + 1);
+            let overflow = &mut self.overflow[self.cursor.1 as usize];
+            if overflow.len() <= x {
+                overflow.resize(x + 1, Cell::default());
+            }
+            overflow[x] = Cell::new(ch, self.attr);
+            self.edge_x = Some(x);
+        }
         if cursor_inc {
             self.cursor_inc();
         }
     }
 
+    // DECSC
+    pub fn save_cursor(&mut self) {
+        self.saved_cursor = Some((self.cursor, self.attr));
+    }
+
+    // DECRC, home with default attributes if nothing saved
+    pub fn restore_cursor(&mut self) {
+        let (cursor, attr) = self.saved_cursor.unwrap_or(((0, 0), Attr::default()));
+        self.move_cursor(cursor.0, cursor.1, true);
+        self.attr = attr;
+    }
+
     pub fn move_cursor(&mut self, x: i32, y: i32, abs: bool) {
+        self.edge_x = None;
         if abs {
             self.cursor.0 = x;
             self.cursor.1 = y;
@@ -86,44 +655,88 @@ impl ScreenBuffer {
         self.cursor.1 = self.cursor.1.min(self.size.1 - 1).max(0);
     }
 
+    // push lines up to last non-blank line to scrollback
+    pub fn save_screen(&mut self) {
+        let blank = |cell: &Cell| (cell.ch == 0 || cell.ch == b' ') && cell.attr == Attr::default();
+        let rows = (0..self.size.1)
+            .rev()
+            .find(|y| {
+                !self.buffer[(y * self.size.0) as usize..((y + 1) * self.size.0) as usize]
+                    .iter()
+                    .all(blank)
+            })
+            .map_or(0, |y| y + 1);
+        for y in 0..rows {
+            self.push_scrollback(y);
+        }
+    }
+
+    // DECSED and DECSEL skip protected cells
+    fn erase_cell(&mut self, index: i32, selective: bool) {
+        if selective && self.buffer[index as usize].attr.protected {
+            return;
+        }
+        self.set_cell(index, Cell::new(b' ', self.erased_attr()));
+        if index % self.size.0 == self.size.0 - 1 {
+            self.overflow[(index / self.size.0) as usize].clear();
+        }
+    }
+
+    // DECSCA
+    pub fn set_protected(&mut self, protected: bool) {
+        self.attr.protected = protected;
+    }
+
+    // OSC 8, applied to new chars
+    pub fn set_link(&mut self, link: u16) {
+        self.attr.link = link;
+    }
+
     // match csi definition
-    pub fn erase_display(&mut self, param: i32) {
+    pub fn erase_display(&mut self, param: i32, selective: bool) {
         if param == 0 {
             for x in 0..self.size.0 {
                 for y in self.cursor.1..self.size.1 {
-                    self.buffer[(x + y * self.size.0) as usize] = b' ';
+                    self.erase_cell(x + y * self.size.0, selective);
                 }
             }
         } else if param == 1 {
             for x in 0..self.size.0 {
                 for y in 0..=self.cursor.1 {
-                    self.buffer[(x + y * self.size.0) as usize] = b' ';
+                    self.erase_cell(x + y * self.size.0, selective);
                 }
             }
         } else if param == 2 {
             for x in 0..self.size.0 {
                 for y in 0..self.size.1 {
-                    self.buffer[(x + y * self.size.0) as usize] = b' ';
+                    self.erase_cell(x + y * self.size.0, selective);
                 }
             }
+            if !selective {
+                self.images.clear();
+            }
+        } else if param == 3 {
+            // xterm, clear scrollback only
+            self.scrollback.clear();
+            self.view_offset = 0;
         } else {
             println!("Unsupported EL Param!")
         }
     }
 
     // match csi definition
-    pub fn erase_line(&mut self, param: i32) {
+    pub fn erase_line(&mut self, param: i32, selective: bool) {
         if param == 0 {
             for i in self.cursor.0..self.size.0 {
-                self.buffer[(i + self.cursor.1 * self.size.0) as usize] = b' ';
+                self.erase_cell(i + self.cursor.1 * self.size.0, selective);
             }
         } else if param == 1 {
             for i in 0..=self.cursor.0 {
-                self.buffer[(i + self.cursor.1 * self.size.0) as usize] = b' ';
+                self.erase_cell(i + self.cursor.1 * self.size.0, selective);
             }
         } else if param == 2 {
             for i in 0..self.size.0 {
-                self.buffer[(i + self.cursor.1 * self.size.0) as usize] = b' ';
+                self.erase_cell(i + self.cursor.1 * self.size.0, selective);
             }
         } else {
             println!("Unsupported EL Param!")
@@ -135,15 +748,487 @@ impl ScreenBuffer {
             println!("Error: only implemented report_cursor for final byte: n");
             return None;
         }
-        let mut report = vec![27, b'['];
-        report.extend(self.cursor.1.to_string().into_bytes());
-        report.push(b';');
-        report.extend(self.cursor.0.to_string().into_bytes());
-        report.push(b'R');
-        Some(report)
+        // cursor is zero based, report is one based
+        Some(format!("\x1b[{};{}R", self.cursor.1 + 1, self.cursor.0 + 1).into_bytes())
+    }
+
+    // match csi definition, each param is split by ':' into sub-params
+    pub fn sgr(&mut self, params: &[Vec<i32>]) {
+        let mut iter = params.iter();
+        while let Some(param) = iter.next() {
+            let sub = &param[1..];
+            match param[0] {
+                // protection and hyperlink are not SGR attributes
+                0 => {
+                    self.attr = Attr {
+                        protected: self.attr.protected,
+                        link: self.attr.link,
+                        ..Attr::default()
+                    }
+                }
+                1 => self.attr.bold = true,
+                3 => self.attr.italic = true,
+                7 => self.attr.reverse = true,
+                8 => self.attr.concealed = true,
+                4 => {
+                    self.attr.underline = match sub.first() {
+                        None | Some(1) => UnderlineStyle::Single,
+                        Some(0) => UnderlineStyle::None,
+                        Some(2) => UnderlineStyle::Double,
+                        Some(3) => UnderlineStyle::Curly,
+                        Some(4) => UnderlineStyle::Dotted,
+                        Some(5) => UnderlineStyle::Dashed,
+                        Some(style) => {
+                            println!("Unsupported underline style {}", style);
+                            continue;
+                        }
+                    }
+                }
+                22 => self.attr.bold = false,
+                23 => self.attr.italic = false,
+                27 => self.attr.reverse = false,
+                28 => self.attr.concealed = false,
+                24 => self.attr.underline = UnderlineStyle::None,
+                53 => self.attr.overline = true,
+                55 => self.attr.overline = false,
+                code @ 30..=37 => self.attr.fg = Color::Named((code - 30) as u8),
+                code @ 40..=47 => self.attr.bg = Color::Named((code - 40) as u8),
+                39 => self.attr.fg = Color::Default,
+                49 => self.attr.bg = Color::Default,
+                code @ 90..=97 => self.attr.fg = Color::Named((code - 90 + 8) as u8),
+                code @ 100..=107 => self.attr.bg = Color::Named((code - 100 + 8) as u8),
+                38 => {
+                    if let Some(color) = extended_color(sub, &mut iter) {
+                        self.attr.fg = color;
+                    }
+                }
+                48 => {
+                    if let Some(color) = extended_color(sub, &mut iter) {
+                        self.attr.bg = color;
+                    }
+                }
+                58 => {
+                    if let Some(color) = extended_color(sub, &mut iter) {
+                        self.attr.underline_color = color;
+                    }
+                }
+                59 => self.attr.underline_color = Color::Default,
+                code => println!("Unsupported SGR param {}", code),
+            }
+        }
+    }
+
+    // SGR params of current attributes, for DECRQSS
+    pub fn sgr_string(&self) -> String {
+        self.attr.sgr_params()
+    }
+
+    // row of scrollback lines followed by screen lines,
+    // and whether it is soft wrapped into next row
+    fn history_row(&self, y: i32) -> (&[Cell], bool) {
+        let len = self.scrollback.len() as i32;
+        if y < len {
+            return (&self.scrollback[y as usize].cells, false);
+        }
+        let y = y - len;
+        let start = (y * self.size.0) as usize;
+        (
+            &self.buffer[start..start + self.size.0 as usize],
+            self.wrapped[y as usize],
+        )
+    }
+
+    // history row shown at screen row y
+    pub fn view_row(&self, y: i32) -> i32 {
+        self.scrollback.len() as i32 - self.view_offset + y
+    }
+
+    // text from start to end exclusive, positions are (x, history row),
+    // soft wrapped lines are joined
+    pub fn text_range(&self, start: (i32, i32), end: (i32, i32), ansi: bool) -> String {
+        let mut result = String::new();
+        // attributes of last written cell
+        let mut attr = Attr::default();
+        for y in start.1..=end.1 {
+            let (row, wrapped) = self.history_row(y);
+            let x0 = if y == start.1 { start.0 as usize } else { 0 };
+            let x1 = if y == end.1 {
+                (end.0 as usize).min(row.len())
+            } else {
+                row.len()
+            };
+            let wrapped = wrapped && x1 == row.len();
+            let mut cells = row.get(x0..x1).unwrap_or(&[]);
+            // trailing spaces are trimmed, unless colored for ansi
+            while let Some((cell, rest)) = cells.split_last() {
+                if wrapped
+                    || (cell.ch != 0 && cell.ch != b' ')
+                    || (ansi && cell.attr != Attr::default())
+                {
+                    break;
+                }
+                cells = rest;
+            }
+            for cell in cells {
+                if ansi && cell.attr != attr {
+                    attr = cell.attr;
+                    result.push_str(&format!("\x1b[{}m", attr.sgr_params()));
+                }
+                result.push(match cell.ch {
+                    0 => ' ',
+                    ch => char::from(ch),
+                });
+            }
+            if !wrapped && y != end.1 {
+                result.push('\n');
+            }
+        }
+        if attr != Attr::default() {
+            result.push_str("\x1b[0m");
+        }
+        result
+    }
+
+    pub fn mark(&mut self, mark: PromptMark) {
+        self.marks[self.cursor.1 as usize].push((self.cursor.0, mark));
+    }
+
+    // (x, history row) of marks, in order
+    fn mark_positions(&self) -> Vec<((i32, i32), PromptMark)> {
+        let lines = self
+            .scrollback
+            .iter()
+            .map(|line| &line.marks)
+            .chain(self.marks.iter());
+        let mut result = Vec::new();
+        for (y, marks) in lines.enumerate() {
+            for (x, mark) in marks.iter() {
+                result.push(((*x, y as i32), *mark));
+            }
+        }
+        result
+    }
+
+    // output of last command from OSC 133 C to D,
+    // or to cursor if command is still running
+    pub fn last_output(&self) -> Option<String> {
+        let marks = self.mark_positions();
+        let id = marks
+            .iter()
+            .rposition(|(_, mark)| *mark == PromptMark::Output)?;
+        let start = marks[id].0;
+        let end = match marks[id..]
+            .iter()
+            .find(|(_, mark)| *mark == PromptMark::End)
+        {
+            Some((pos, _)) => *pos,
+            None => (self.cursor.0, self.scrollback.len() as i32 + self.cursor.1),
+        };
+        if (end.1, end.0) <= (start.1, start.0) {
+            return Some(String::new());
+        }
+        // newline before end mark is not part of output
+        let end = if end.0 == 0 {
+            (i32::MAX, end.1 - 1)
+        } else {
+            end
+        };
+        Some(self.text_range(start, end, false))
+    }
+
+    // visible text, trailing spaces are trimmed
+    pub fn text(&self) -> String {
+        let mut lines = Vec::new();
+        for y in 0..self.size.1 {
+            let line: String = self.buffer
+                [(y * self.size.0) as usize..((y + 1) * self.size.0) as usize]
+                .iter()
+                .map(|cell| {
+                    if cell.ch == 0 {
+                        ' '
+                    } else {
+                        char::from(cell.ch)
+                    }
+                })
+                .collect();
+            lines.push(line.trim_end().to_string());
+        }
+        lines.join("\n")
     }
 
-    pub fn get_render_data(&mut self) -> (&Vec<u8>, (i32, i32)) {
+    pub fn get_render_data(&self) -> (&Vec<Cell>, (i32, i32)) {
         (&self.buffer, self.cursor)
     }
+
+    pub fn mark_all_dirty(&mut self) {
+        for dirty in self.dirty.iter_mut() {
+            *dirty = true;
+        }
+    }
+
+    // cells changed since last call, with (x, y) position,
+    // old and new cursor cells are always included
+    pub fn take_damage(&mut self) -> Vec<((i32, i32), Cell)> {
+        let width = self.size.0;
+        for (x, y) in [self.damage_cursor, self.cursor].iter() {
+            if let Some(dirty) = self.dirty.get_mut((x + y * width) as usize) {
+                *dirty = true;
+            }
+        }
+        self.damage_cursor = self.cursor;
+        let mut result = Vec::new();
+        for (index, dirty) in self.dirty.iter_mut().enumerate() {
+            if *dirty {
+                let index = index as i32;
+                result.push(((index % width, index / width), self.buffer[index as usize]));
+                *dirty = false;
+            }
+        }
+        result
+    }
+
+    pub fn scrolled(&self) -> u64 {
+        self.scrolled
+    }
+
+    pub fn view_offset(&self) -> i32 {
+        self.view_offset
+    }
+
+    pub fn h_offset(&self) -> i32 {
+        if self.autowrap {
+            0
+        } else {
+            self.h_offset
+        }
+    }
+
+    // cell shown at screen position, scrolled back lines are on top
+    pub fn view_cell(&self, x: i32, y: i32) -> Cell {
+        let h_offset = self.h_offset();
+        if y < self.view_offset {
+            let line = &self.scrollback[self.scrollback.len() - (self.view_offset - y) as usize];
+            line.cells
+                .get((x + h_offset) as usize)
+                .copied()
+                .unwrap_or_default()
+        } else if h_offset == 0 {
+            self.buffer[(x + (y - self.view_offset) * self.size.0) as usize]
+        } else {
+            let y = y - self.view_offset;
+            let x = x + h_offset;
+            let overflow = &self.overflow[y as usize];
+            if x < self.size.0 - 1 || (overflow.is_empty() && x < self.size.0) {
+                self.buffer[(x + y * self.size.0) as usize]
+            } else {
+                overflow
+                    .get((x - self.size.0 + 1) as usize)
+                    .copied()
+                    .unwrap_or_default()
+            }
+        }
+    }
+
+    // longest line in scrollback or screen
+    fn max_width(&self) -> i32 {
+        let screen = self
+            .overflow
+            .iter()
+            .map(|overflow| self.size.0 - 1 + overflow.len() as i32)
+            .max()
+            .unwrap_or(0);
+        self.scrollback
+            .iter()
+            .map(|line| line.cells.len() as i32)
+            .fold(screen, i32::max)
+    }
+
+    // positive n scrolls right, no-op with autowrap,
+    // return false if there is nothing to scroll
+    pub fn scroll_horizontal(&mut self, n: i32) -> bool {
+        let max_offset = self.max_width() - self.size.0;
+        if self.autowrap || max_offset <= 0 {
+            return false;
+        }
+        self.h_offset = (self.h_offset + n).min(max_offset).max(0);
+        true
+    }
+
+    // positive n scrolls back into history
+    pub fn scroll_view(&mut self, n: i32) {
+        self.view_offset = (self.view_offset + n)
+            .min(self.scrollback.len() as i32)
+            .max(0);
+    }
+
+    // index of prompt lines, scrollback lines first
+    fn prompt_lines(&self) -> Vec<i32> {
+        self.scrollback
+            .iter()
+            .map(|line| &line.marks)
+            .chain(self.marks.iter())
+            .enumerate()
+            .filter(|(_, marks)| marks.iter().any(|(_, mark)| *mark == PromptMark::Prompt))
+            .map(|(id, _)| id as i32)
+            .collect()
+    }
+
+    // scroll view to put previous or next prompt on top,
+    // no-op without prompt marks
+    pub fn jump_prompt(&mut self, forward: bool) {
+        let len = self.scrollback.len() as i32;
+        let top = len - self.view_offset;
+        let lines = self.prompt_lines();
+        let target = if forward {
+            lines.into_iter().find(|&y| y > top)
+        } else {
+            lines.into_iter().rev().find(|&y| y < top)
+        };
+        match target {
+            Some(y) => self.view_offset = (len - y).max(0),
+            // nothing below, back to bottom
+            None if forward => self.view_offset = 0,
+            None => {}
+        }
+    }
+}
+
+// 5;n or 2;r;g;b following 38/48/58,
+// or 5:n, 2:r:g:b and 2::r:g:b as sub-params
+fn extended_color(sub: &[i32], iter: &mut std::slice::Iter<Vec<i32>>) -> Option<Color> {
+    if !sub.is_empty() {
+        return match *sub {
+            [5, n] => Some(Color::Indexed(n as u8)),
+            [2, r, g, b] | [2, _, r, g, b] => Some(Color::Rgb(r as u8, g as u8, b as u8)),
+            _ => None,
+        };
+    }
+    let mut next = || iter.next().map(|param| param[0]);
+    match next() {
+        Some(5) => next().map(|n| Color::Indexed(n as u8)),
+        Some(2) => {
+            let r = next()?;
+            let g = next()?;
+            let b = next()?;
+            Some(Color::Rgb(r as u8, g as u8, b as u8))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LF is CR LF like pty output with onlcr
+    fn write(screen: &mut ScreenBuffer, text: &[u8]) {
+        for &ch in text {
+            if ch == b'\n' {
+                screen.set_char(b'\r', true);
+            }
+            screen.set_char(ch, true);
+        }
+    }
+
+    fn view_line(screen: &ScreenBuffer, y: i32) -> String {
+        (0..screen.size.0)
+            .map(|x| char::from(screen.view_cell(x, y).ch))
+            .collect::<String>()
+            .trim_end_matches(char::from(0))
+            .to_string()
+    }
+
+    // prompt, command and output with OSC 133 marks
+    fn run_command(screen: &mut ScreenBuffer, command: &[u8], output: &[u8]) {
+        screen.mark(PromptMark::Prompt);
+        write(screen, b"$ ");
+        screen.mark(PromptMark::Command);
+        write(screen, command);
+        screen.mark(PromptMark::Output);
+        write(screen, output);
+        screen.mark(PromptMark::End);
+    }
+
+    #[test]
+    fn prompt_mark_regions() {
+        let mut screen = ScreenBuffer::new((10, 6));
+        run_command(&mut screen, b"ls\n", b"a\nb\n");
+        let marks = screen.mark_positions();
+        assert_eq!(
+            marks,
+            vec![
+                ((0, 0), PromptMark::Prompt),
+                ((2, 0), PromptMark::Command),
+                ((0, 1), PromptMark::Output),
+                ((0, 3), PromptMark::End),
+            ]
+        );
+        assert_eq!(screen.last_output().as_deref(), Some("a\nb"));
+    }
+
+    #[test]
+    fn last_output_longer_than_screen() {
+        let mut screen = ScreenBuffer::new((10, 4));
+        run_command(&mut screen, b"seq 8\n", b"1\n2\n3\n4\n5\n6\n7\n8\n");
+        assert_eq!(
+            screen.last_output().as_deref(),
+            Some("1\n2\n3\n4\n5\n6\n7\n8")
+        );
+    }
+
+    #[test]
+    fn marks_survive_resize() {
+        let mut screen = ScreenBuffer::new((10, 6));
+        run_command(&mut screen, b"cmd\n", b"out1\nout2\n");
+        screen.resize((8, 3));
+        assert_eq!(screen.last_output().as_deref(), Some("out1\nout2"));
+        screen.resize((8, 2));
+        assert_eq!(screen.last_output().as_deref(), Some("out1\nout2"));
+    }
+
+    #[test]
+    fn jump_back_to_newest_prompt() {
+        let mut screen = ScreenBuffer::new((10, 4));
+        for prompt in [b"$ one\n", b"$ two\n"].iter() {
+            screen.mark(PromptMark::Prompt);
+            write(&mut screen, *prompt);
+            write(&mut screen, b"a\nb\nc\nd\n");
+        }
+        screen.jump_prompt(false);
+        assert_eq!(view_line(&screen, 0), "$ two");
+        screen.jump_prompt(false);
+        assert_eq!(view_line(&screen, 0), "$ one");
+        screen.jump_prompt(true);
+        assert_eq!(view_line(&screen, 0), "$ two");
+    }
+
+    #[test]
+    fn wrapped_line_rejoins_when_widened() {
+        let mut screen = ScreenBuffer::new((5, 3));
+        write(&mut screen, b"abcdefgh\nxy");
+        assert_eq!(view_line(&screen, 0), "abcde");
+        assert_eq!(view_line(&screen, 1), "fgh");
+        screen.resize((10, 3));
+        assert_eq!(view_line(&screen, 0), "abcdefgh");
+        assert_eq!(view_line(&screen, 1), "xy");
+        assert_eq!(screen.cursor, (2, 1));
+        // hard line break is kept
+        screen.resize((20, 3));
+        assert_eq!(view_line(&screen, 1), "xy");
+    }
+
+    #[test]
+    fn damage_has_changed_and_cursor_cells() {
+        let mut screen = ScreenBuffer::new((10, 3));
+        assert_eq!(screen.take_damage().len(), 30);
+        screen.move_cursor(5, 1, true);
+        screen.take_damage();
+        write(&mut screen, b"x");
+        screen.move_cursor(2, 2, true);
+        let positions: Vec<(i32, i32)> = screen.take_damage().iter().map(|(pos, _)| *pos).collect();
+        assert_eq!(positions, vec![(5, 1), (2, 2)]);
+        let damage = screen.take_damage();
+        assert_eq!(damage.len(), 1);
+        assert_eq!(damage[0].0, (2, 2));
+    }
 }
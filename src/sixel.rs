@@ -0,0 +1,178 @@
+// sixel graphics, see https://vt100.net/docs/vt3xx-gp/chapter14.html
+
+// larger images are clipped
+const MAX_SIZE: i32 = 2048;
+
+// higher color register numbers use the last register
+const MAX_REGISTERS: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct SixelImage {
+    pub width: i32,
+    pub height: i32,
+    // row major RGB, None is transparent
+    pub pixels: Vec<Option<[u8; 3]>>,
+}
+
+impl SixelImage {
+    fn new() -> SixelImage {
+        SixelImage {
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+        }
+    }
+
+    fn grow(&mut self, width: i32, height: i32) {
+        if width <= self.width && height <= self.height {
+            return;
+        }
+        let width = width.max(self.width).min(MAX_SIZE);
+        let height = height.max(self.height).min(MAX_SIZE);
+        let mut pixels = vec![None; (width * height) as usize];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                pixels[(x + y * width) as usize] = self.pixels[(x + y * self.width) as usize];
+            }
+        }
+        self.width = width;
+        self.height = height;
+        self.pixels = pixels;
+    }
+
+    // 6 pixels of one column, lowest bit on top
+    fn put_sixel(&mut self, x: i32, band: i32, bits: u8, color: [u8; 3]) {
+        if x >= MAX_SIZE || band * 6 + 6 > MAX_SIZE {
+            return;
+        }
+        self.grow(x + 1, band * 6 + 6);
+        for i in 0..6 {
+            if bits & (1 << i) != 0 {
+                self.pixels[(x + (band * 6 + i) * self.width) as usize] = Some(color);
+            }
+        }
+    }
+}
+
+// hue in degrees, lightness and saturation in percent
+fn hls_to_color(h: i32, l: i32, s: i32) -> [f32; 4] {
+    let l = l as f32 / 100.;
+    let s = s as f32 / 100.;
+    let c = (1. - (2. * l - 1.).abs()) * s;
+    // sixel hue 0 is blue
+    let h = ((h + 240) % 360) as f32 / 60.;
+    let x = c * (1. - (h % 2. - 1.).abs());
+    let (r, g, b) = match h as i32 {
+        0 => (c, x, 0.),
+        1 => (x, c, 0.),
+        2 => (0., c, x),
+        3 => (0., x, c),
+        4 => (x, 0., c),
+        _ => (c, 0., x),
+    };
+    let m = l - c / 2.;
+    [r + m, g + m, b + m, 1.]
+}
+
+fn to_rgb(color: [f32; 4]) -> [u8; 3] {
+    [
+        (color[0] * 255.) as u8,
+        (color[1] * 255.) as u8,
+        (color[2] * 255.) as u8,
+    ]
+}
+
+// numbers separated by ';' at start of data, and rest of data
+fn parse_numbers(data: &[u8]) -> (Vec<i32>, &[u8]) {
+    let end = data
+        .iter()
+        .position(|ch| !ch.is_ascii_digit() && *ch != b';')
+        .unwrap_or(data.len());
+    let numbers = String::from_utf8_lossy(&data[..end])
+        .split(';')
+        .map(|x| x.parse::<i32>().unwrap_or(0))
+        .collect();
+    (numbers, &data[end..])
+}
+
+// data is DCS string after the final byte q,
+// color registers start from palette
+pub fn parse(mut data: &[u8], palette: &[[f32; 4]]) -> SixelImage {
+    let mut image = SixelImage::new();
+    let mut registers: Vec<[u8; 3]> = palette.iter().copied().map(to_rgb).collect();
+    let mut color = registers[0];
+    let mut x = 0;
+    let mut band = 0;
+    while let Some((&ch, rest)) = data.split_first() {
+        data = rest;
+        match ch {
+            // raster attributes, aspect ratio is ignored
+            b'"' => {
+                let (numbers, rest) = parse_numbers(data);
+                data = rest;
+                if let [_, _, width, height] = numbers[..] {
+                    image.grow(width, height);
+                }
+            }
+            b'#' => {
+                let (numbers, rest) = parse_numbers(data);
+                data = rest;
+                let index = (numbers[0].max(0) as usize).min(MAX_REGISTERS - 1);
+                if registers.len() <= index {
+                    registers.resize(index + 1, [0, 0, 0]);
+                }
+                match numbers[..] {
+                    [_, 1, h, l, s] => registers[index] = to_rgb(hls_to_color(h, l, s)),
+                    [_, 2, r, g, b] => {
+                        let percent = |x: i32| (x.clamp(0, 100) * 255 / 100) as u8;
+                        registers[index] = [percent(r), percent(g), percent(b)];
+                    }
+                    _ => {}
+                }
+                color = registers[index];
+            }
+            b'!' => {
+                let (numbers, rest) = parse_numbers(data);
+                if let Some((&ch, rest)) = rest.split_first() {
+                    data = rest;
+                    if (0x3f..=0x7e).contains(&ch) {
+                        for _ in 0..numbers[0].max(1).min(MAX_SIZE - x) {
+                            image.put_sixel(x, band, ch - 0x3f, color);
+                            x += 1;
+                        }
+                    }
+                } else {
+                    data = rest;
+                }
+            }
+            b'$' => x = 0,
+            b'-' => {
+                x = 0;
+                band += 1;
+            }
+            0x3f..=0x7e => {
+                image.put_sixel(x, band, ch - 0x3f, color);
+                x += 1;
+            }
+            _ => {}
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_register_is_clamped() {
+        let image = parse(b"#2000000000;2;100;0;0~", &[[0., 0., 0., 1.]]);
+        assert_eq!(image.pixels[0], Some([255, 0, 0]));
+    }
+
+    #[test]
+    fn huge_raster_is_clipped() {
+        let image = parse(b"\"1;1;4096;4096", &[[0., 0., 0., 1.]]);
+        assert_eq!((image.width, image.height), (MAX_SIZE, MAX_SIZE));
+    }
+}
@@ -0,0 +1,113 @@
+// window title and icon title, set by OSC 0/1/2 and XTWINOPS
+
+// same limit as xterm
+const STACK_LIMIT: usize = 10;
+
+// reported text is written to pty, no controls to avoid injecting input
+fn report(kind: char, text: &str) -> Vec<u8> {
+    let text: String = text.chars().filter(|ch| !ch.is_control()).collect();
+    format!("\x1b]{}{}\x1b\\", kind, text).into_bytes()
+}
+
+pub struct Title {
+    title: String,
+    icon: String,
+    // XTPUSHTITLE, None if not pushed
+    stack: Vec<(Option<String>, Option<String>)>,
+    // window title should be updated
+    changed: bool,
+}
+
+impl Title {
+    pub fn new(title: &str) -> Title {
+        Title {
+            title: title.to_string(),
+            icon: title.to_string(),
+            stack: Vec::new(),
+            changed: false,
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+        self.changed = true;
+    }
+
+    // not shown, only reported
+    pub fn set_icon(&mut self, icon: &str) {
+        self.icon = icon.to_string();
+    }
+
+    // redraw title even if text is same
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+
+    // true once after each change
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    // which is XTWINOPS param, 0 both, 1 icon, 2 title
+    pub fn push(&mut self, which: i32) {
+        if self.stack.len() >= STACK_LIMIT {
+            return;
+        }
+        let icon = Some(self.icon.clone()).filter(|_| which != 2);
+        let title = Some(self.title.clone()).filter(|_| which != 1);
+        self.stack.push((icon, title));
+    }
+
+    pub fn pop(&mut self, which: i32) {
+        if let Some((icon, title)) = self.stack.pop() {
+            if let Some(icon) = icon.filter(|_| which != 2) {
+                self.set_icon(&icon);
+            }
+            if let Some(title) = title.filter(|_| which != 1) {
+                self.set_title(&title);
+            }
+        }
+    }
+
+    pub fn clear_stack(&mut self) {
+        self.stack.clear();
+    }
+
+    // reply of CSI 20 t, OSC L icon ST
+    pub fn report_icon(&self) -> Vec<u8> {
+        report('L', &self.icon)
+    }
+
+    // reply of CSI 21 t, OSC l title ST
+    pub fn report_title(&self) -> Vec<u8> {
+        report('l', &self.title)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_set_pop() {
+        let mut title = Title::new("fsdterm");
+        title.set_title("one");
+        title.set_icon("icon");
+        title.push(0);
+        title.set_title("two");
+        title.set_icon("other");
+        assert!(title.take_changed());
+        title.pop(2);
+        assert_eq!(title.title(), "one");
+        assert_eq!(title.report_icon(), b"\x1b]Lother\x1b\\");
+        assert!(title.take_changed());
+        // stack is empty, nothing changes
+        title.pop(0);
+        assert_eq!(title.title(), "one");
+        assert!(!title.take_changed());
+    }
+}